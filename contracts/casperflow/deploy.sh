@@ -35,7 +35,8 @@ if casper-client put-transaction session --help &>/dev/null; then
         --session-arg "contract_name:string='$CONTRACT_NAME'" \
         --session-arg "token_name:string='$TOKEN_NAME'" \
         --session-arg "token_symbol:string='$TOKEN_SYMBOL'" \
-        --session-arg "total_supply:u256='$TOTAL_SUPPLY'"
+        --session-arg "total_supply:u256='$TOTAL_SUPPLY'" \
+        --session-arg "chain_name:string='$CHAIN_NAME'"
 else
     echo "Using put-deploy (legacy)..."
     casper-client put-deploy \
@@ -47,7 +48,8 @@ else
         --session-arg "contract_name:string='$CONTRACT_NAME'" \
         --session-arg "token_name:string='$TOKEN_NAME'" \
         --session-arg "token_symbol:string='$TOKEN_SYMBOL'" \
-        --session-arg "total_supply:u256='$TOTAL_SUPPLY'"
+        --session-arg "total_supply:u256='$TOTAL_SUPPLY'" \
+        --session-arg "chain_name:string='$CHAIN_NAME'"
 fi
 
 echo ""
@@ -11,15 +11,17 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
 
-use alloc::{format, string::String, vec};
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, vec, vec::Vec};
 use casper_contract::{
-    contract_api::{runtime, storage},
+    contract_api::{cryptography, runtime, storage},
     unwrap_or_revert::UnwrapOrRevert,
 };
 use casper_types::{
+    bytesrepr::{self, FromBytes, ToBytes},
+    contract_messages::{MessagePayload, MessageTopicOperation},
     contracts::{EntryPoint, NamedKeys},
-    ApiError, CLType, CLValue, EntryPointAccess, EntryPointType, EntryPoints, Key, Parameter,
-    URef, U256, U512,
+    ApiError, CLType, CLTyped, CLValue, EntryPointAccess, EntryPointType, EntryPoints,
+    HashAlgorithm, Key, Parameter, PublicKey, Signature, URef, U256, U512,
 };
 
 // Constants
@@ -35,6 +37,28 @@ const KEY_VAULT_TOTAL: &str = "vault_total";
 const KEY_LIQUID_STAKING_RATIO: &str = "liquid_staking_ratio";
 const KEY_TOTAL_LIQUID_STAKED: &str = "total_liquid_staked";
 const KEY_ORDER_COUNTER: &str = "order_counter";
+const KEY_BEST_BID: &str = "best_bid";
+const KEY_BEST_ASK: &str = "best_ask";
+const KEY_POSITION_COUNTER: &str = "position_counter";
+const KEY_LIQUIDATOR_REWARD_BPS: &str = "liquidator_reward_bps";
+const KEY_INSURANCE_FUND: &str = "insurance_fund";
+const KEY_MARKET_COUNTER: &str = "market_counter";
+const KEY_TREASURY: &str = "treasury";
+const KEY_INSURANCE_FEE_SHARE_BPS: &str = "insurance_fee_share_bps";
+const KEY_MAX_ACCOUNT_NOTIONAL: &str = "max_account_notional";
+const KEY_MAX_POSITIONS_PER_ACCOUNT: &str = "max_positions_per_account";
+const KEY_EXPIRY_BOUNTY_BPS: &str = "expiry_bounty_bps";
+const KEY_MIN_KEEPER_BOND: &str = "min_keeper_bond";
+const KEY_EXECUTION_FEE: &str = "execution_fee";
+const KEY_FUNDING_UPDATE_REWARD: &str = "funding_update_reward";
+// (chain name, contract package hash) bound into signed order messages
+const KEY_SIGNING_DOMAIN: &str = "signing_domain";
+const KEY_SPOT_MAKER_FEE_BPS: &str = "spot_maker_fee_bps";
+const KEY_SPOT_TAKER_FEE_BPS: &str = "spot_taker_fee_bps";
+const KEY_SPOT_MAKER_REBATE_BPS: &str = "spot_maker_rebate_bps";
+const KEY_VOLUME_TIERS: &str = "volume_tiers";
+const KEY_STAKING_TIERS: &str = "staking_tiers";
+const KEY_TOTAL_FEE_STAKED: &str = "total_fee_staked";
 
 const DICT_BALANCES: &str = "balances";
 const DICT_ALLOWANCES: &str = "allowances";
@@ -44,27 +68,237 @@ const DICT_VAULT: &str = "vault_shares";
 const DICT_LIQUID_STAKERS: &str = "liquid_stakers";
 const DICT_STCSPR_BALANCES: &str = "stcspr_balances";
 const DICT_ORDERS: &str = "orders";
+const DICT_MARKETS: &str = "markets";
+const DICT_MARKET_STATE: &str = "market_state";
+const DICT_ACCOUNT_NOTIONAL: &str = "account_notional";
+const DICT_ACCOUNT_POSITIONS: &str = "account_positions";
+const DICT_MARKET_POSITIONS: &str = "market_positions";
+const DICT_MARKET_POSITION_COUNT: &str = "market_position_count";
+const DICT_POSITION_SLOTS: &str = "position_slots";
+const DICT_TRADE_HISTORY: &str = "trade_history";
+const DICT_TRADE_COUNT: &str = "trade_count";
+const DICT_KEEPERS: &str = "keepers";
+const DICT_KEEPER_SLASHES: &str = "keeper_slashes";
+const DICT_FUNDING_REWARDS: &str = "funding_rewards";
+const DICT_ADL_QUEUE: &str = "adl_queue";
+const DICT_LOCKED_CSPR: &str = "locked_cspr";
+const DICT_LOCKED_CFLOW: &str = "locked_cflow";
+const DICT_OPEN_ORDERS: &str = "open_orders";
+const DICT_OPEN_ORDER_COUNT: &str = "open_order_count";
+const DICT_ORDER_SLOTS: &str = "order_slots";
+const DICT_BOOK_LEVELS: &str = "book_levels";
+const DICT_BOOK_LINKS: &str = "book_links";
+const DICT_POSITION_ORDERS: &str = "position_orders";
+const DICT_ORDER_NONCES: &str = "order_nonces";
+const DICT_OWNER_ORDERS: &str = "owner_orders";
+const DICT_OWNER_ORDER_COUNT: &str = "owner_order_count";
+const DICT_SIGNED_FILLS: &str = "signed_fills";
+const DICT_VOLUME_BUCKETS: &str = "volume_buckets";
+const DICT_VOLUME_TOTALS: &str = "volume_totals";
+const DICT_FEE_STAKES: &str = "fee_stakes";
 
 const ARG_CONTRACT_NAME: &str = "contract_name";
 const ARG_TOKEN_NAME: &str = "token_name";
 const ARG_TOKEN_SYMBOL: &str = "token_symbol";
 const ARG_TOTAL_SUPPLY: &str = "total_supply";
+const ARG_CHAIN_NAME: &str = "chain_name";
 const ARG_RECIPIENT: &str = "recipient";
 const ARG_OWNER: &str = "owner";
 const ARG_SPENDER: &str = "spender";
 const ARG_AMOUNT: &str = "amount";
 const ARG_ORDER_ID: &str = "order_id";
+const ARG_ORDER_IDS: &str = "order_ids";
+const ARG_DEPTH: &str = "depth";
+const ARG_TIME_IN_FORCE: &str = "time_in_force";
+const ARG_EXPIRES_AT: &str = "expires_at";
+const ARG_PRICE_HINT: &str = "price_hint";
+const ARG_EXPIRY_BOUNTY_BPS: &str = "expiry_bounty_bps";
+const ARG_STOP_LOSS_PRICE: &str = "stop_loss_price";
+const ARG_TAKE_PROFIT_PRICE: &str = "take_profit_price";
+const ARG_TRAIL_AMOUNT: &str = "trail_amount";
+const ARG_TRAIL_BPS: &str = "trail_bps";
+const ARG_ASSET: &str = "asset";
+const ARG_SLICE_AMOUNT: &str = "slice_amount";
+const ARG_INTERVAL: &str = "interval";
+const ARG_MIN_PRICE: &str = "min_price";
+const ARG_MAX_PRICE: &str = "max_price";
+const ARG_MIN_KEEPER_BOND: &str = "min_keeper_bond";
+const ARG_EXECUTION_FEE: &str = "execution_fee";
+const ARG_FUNDING_UPDATE_REWARD: &str = "funding_update_reward";
+const ARG_REASON: &str = "reason";
+const ARG_EVIDENCE: &str = "evidence";
+const ARG_INDEX: &str = "index";
+const ARG_MAKER_ORDER: &str = "maker_order";
+const ARG_TAKER_ORDER: &str = "taker_order";
+const ARG_SIGNATURES: &str = "signatures";
+const ARG_NONCE: &str = "nonce";
+const ARG_ORDER_HASH: &str = "order_hash";
+const ARG_STATUS_FILTER: &str = "status_filter";
+const ARG_INSTRUCTIONS: &str = "instructions";
+const ARG_ATOMIC: &str = "atomic";
+const ARG_NEW_PRICE: &str = "new_price";
+const ARG_NEW_AMOUNT: &str = "new_amount";
+const ARG_MAKER_REBATE_BPS: &str = "maker_rebate_bps";
+const ARG_THRESHOLDS: &str = "thresholds";
+const ARG_DISCOUNTS_BPS: &str = "discounts_bps";
 const ARG_ORDER_TYPE: &str = "order_type";
 const ARG_PRICE: &str = "price";
 const ARG_TRIGGER_PRICE: &str = "trigger_price";
+const ARG_LEVERAGE: &str = "leverage";
+const ARG_POSITION_ID: &str = "position_id";
+const ARG_SIDE: &str = "side";
+const ARG_SIZE: &str = "size";
+const ARG_INITIAL_MARGIN_BPS: &str = "initial_margin_bps";
+const ARG_MAINTENANCE_MARGIN_BPS: &str = "maintenance_margin_bps";
+const ARG_LIQUIDATION_PENALTY_BPS: &str = "liquidation_penalty_bps";
+const ARG_LIQUIDATOR_REWARD_BPS: &str = "liquidator_reward_bps";
+const ARG_MARKET_ID: &str = "market_id";
+const ARG_BASE_SYMBOL: &str = "base_symbol";
+const ARG_QUOTE_SYMBOL: &str = "quote_symbol";
+const ARG_ORACLE: &str = "oracle";
+const ARG_MAX_LEVERAGE: &str = "max_leverage";
+const ARG_MAKER_FEE_BPS: &str = "maker_fee_bps";
+const ARG_TAKER_FEE_BPS: &str = "taker_fee_bps";
+const ARG_MAX_LONG_OPEN_INTEREST: &str = "max_long_open_interest";
+const ARG_MAX_SHORT_OPEN_INTEREST: &str = "max_short_open_interest";
+const ARG_ENABLED: &str = "enabled";
+const ARG_FUNDING_RATE_BPS: &str = "funding_rate_bps";
+const ARG_PRICE_IMPACT_BPS: &str = "price_impact_bps";
+const ARG_ACCEPTABLE_PRICE: &str = "acceptable_price";
+const ARG_DEADLINE: &str = "deadline";
+const ARG_OFFSET: &str = "offset";
+const ARG_LIMIT: &str = "limit";
+const ARG_KEEPER: &str = "keeper";
+const ARG_INSURANCE_FEE_SHARE_BPS: &str = "insurance_fee_share_bps";
+const ARG_MAX_ACCOUNT_NOTIONAL: &str = "max_account_notional";
+const ARG_MAX_POSITIONS_PER_ACCOUNT: &str = "max_positions_per_account";
+
+const EVENTS_TOPIC: &str = "events";
+
+// Basis points denominator used by all ratio parameters (10_000 = 100%)
+const BPS_DENOMINATOR: u64 = 10_000;
+
+// Funding indices are cumulative funding per unit of notional scaled by this factor
+const FUNDING_PRECISION: u64 = 1_000_000_000;
+// Funding rates are quoted per hour of block time (milliseconds)
+const FUNDING_INTERVAL: u64 = 3_600_000;
+
+// Auto-deleveraging reduces at most this many positions per bad debt event
+const MAX_ADL_POSITIONS: u32 = 5;
+// ...chosen from a window of at most this many index slots, so bad debt never scans a
+// whole market side
+const MAX_ADL_SCAN: u64 = 50;
+const ADL_SCORE_PRECISION: u64 = 1_000_000;
+
+// Keepers asking to exit wait this long (milliseconds) before their bond is released,
+// leaving time to slash invalid executions
+const KEEPER_UNBONDING_PERIOD: u64 = 7 * 24 * 3_600_000;
+
+// Upper bound on entries returned by paged getters
+const MAX_PAGE_SIZE: u64 = 50;
+// Upper bound on price levels walked or resting orders filled by one book operation
+const MAX_BOOK_ITERATIONS: u64 = 50;
+
+// Fee discounts look at trading volume over a rolling window of whole days
+const VOLUME_WINDOW_DAYS: u64 = 30;
+const DAY_MILLIS: u64 = 86_400_000;
+const MAX_FEE_TIERS: u64 = 10;
+
+const TRADE_OPEN: u8 = 0;
+const TRADE_MODIFY: u8 = 1;
+const TRADE_CLOSE: u8 = 2;
+const TRADE_LIQUIDATION: u8 = 3;
+const TRADE_FUNDING: u8 = 4;
+const TRADE_FEE: u8 = 5;
+const TRADE_ADL: u8 = 6;
+
+const SIDE_LONG: u8 = 0;
+const SIDE_SHORT: u8 = 1;
+
+// Orders on the CFLOW/CSPR spot pair use this market id; registry ids start at 1
+const SPOT_MARKET_ID: u64 = 0;
+// Spot prices are CSPR motes per CFLOW base unit scaled by this factor
+const PRICE_PRECISION: u64 = 1_000_000_000;
+
+// Prefix of every signed order message, so order signatures cannot be replayed as
+// signatures over anything else. The message goes on with KEY_SIGNING_DOMAIN so
+// orders are only valid on the chain and contract package they were signed for.
+const SIGNED_ORDER_DOMAIN: &[u8] = b"CasperFlow signed order v1";
+
+const SIDE_BUY: u8 = 0;
+const SIDE_SELL: u8 = 1;
+
+const ORDER_KIND_LIMIT: u8 = 0;
+const ORDER_KIND_STOP_LOSS: u8 = 1;
+const ORDER_KIND_TAKE_PROFIT: u8 = 2;
+const ORDER_KIND_TRAILING_STOP: u8 = 3;
+const ORDER_KIND_RECURRING: u8 = 4;
+
+// Assets recurring orders can accumulate
+const ASSET_CFLOW: u8 = 0;
+const ASSET_STCSPR: u8 = 1;
+
+const ORDER_STATUS_OPEN: u8 = 0;
+const ORDER_STATUS_CANCELLED: u8 = 1;
+const ORDER_STATUS_EXECUTED: u8 = 2;
+const ORDER_STATUS_EXPIRED: u8 = 3;
+// `get_orders` status filter matching every status
+const ORDER_STATUS_ANY: u8 = u8::MAX;
+
+// Time in force: good-till-cancelled, good-till-time, immediate-or-cancel,
+// fill-or-kill and post-only (rests without taking liquidity)
+const TIF_GTC: u8 = 0;
+const TIF_GTT: u8 = 1;
+const TIF_IOC: u8 = 2;
+const TIF_FOK: u8 = 3;
+const TIF_POST_ONLY: u8 = 4;
+
+// `batch_orders` instruction actions
+const ORDER_ACTION_CREATE: u8 = 0;
+const ORDER_ACTION_CANCEL: u8 = 1;
+const ORDER_ACTION_MODIFY: u8 = 2;
 
 #[repr(u16)]
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
 enum Error {
     InsufficientBalance = 1,
     InsufficientAllowance = 2,
     Unauthorized = 3,
     ContractPaused = 4,
     InvalidAmount = 5,
+    PositionNotFound = 6,
+    InvalidLeverage = 7,
+    InsufficientMargin = 8,
+    PositionNotLiquidatable = 9,
+    PriceNotSet = 10,
+    InvalidMarginParams = 11,
+    InvalidSide = 12,
+    MarketNotFound = 13,
+    MarketDisabled = 14,
+    OpenInterestCapExceeded = 15,
+    AccountNotionalLimitExceeded = 16,
+    MaxPositionsExceeded = 17,
+    SlippageExceeded = 18,
+    DeadlineExpired = 19,
+    OrderNotFound = 20,
+    OrderNotOpen = 21,
+    PriceNotReached = 22,
+    TooManyOrders = 23,
+    OrderBookTooDeep = 24,
+    InvalidTimeInForce = 25,
+    PostOnlyWouldCross = 26,
+    OrderNotFilled = 27,
+    OrderExpired = 28,
+    OrderNotExpired = 29,
+    InvalidTriggerPrice = 30,
+    InvalidOrderKind = 31,
+    SliceNotDue = 32,
+    KeeperBondTooLow = 33,
+    KeeperUnbonding = 34,
+    InvalidSignature = 35,
+    InvalidNonce = 36,
+    InvalidInstruction = 37,
 }
 
 impl From<Error> for ApiError {
@@ -96,390 +330,5117 @@ fn make_key(key: &Key) -> String {
     format!("{:?}", key)
 }
 
-// Token functions
-#[no_mangle]
-pub extern "C" fn name() {
-    let val: String = get_key(KEY_NAME);
-    runtime::ret(CLValue::from_t(val).unwrap_or_revert());
+fn require_admin() {
+    let admin: Key = get_key(KEY_ADMIN);
+    if Key::from(runtime::get_caller()) != admin {
+        runtime::revert(Error::Unauthorized);
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn symbol() {
-    let val: String = get_key(KEY_SYMBOL);
-    runtime::ret(CLValue::from_t(val).unwrap_or_revert());
+fn emit_event(message: String) {
+    runtime::emit_message(EVENTS_TOPIC, &MessagePayload::String(message)).unwrap_or_revert();
 }
 
-#[no_mangle]
-pub extern "C" fn decimals() {
-    let val: u8 = get_key(KEY_DECIMALS);
-    runtime::ret(CLValue::from_t(val).unwrap_or_revert());
+fn require_keeper() {
+    let caller = Key::from(runtime::get_caller());
+    if !is_active_keeper(&read_keeper(&caller)) {
+        runtime::revert(Error::Unauthorized);
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn total_supply() {
-    let val: U256 = get_key(KEY_TOTAL_SUPPLY);
-    runtime::ret(CLValue::from_t(val).unwrap_or_revert());
+fn to_u256(value: U512) -> U256 {
+    let mut bytes = [0u8; 64];
+    value.to_little_endian(&mut bytes);
+    if bytes[32..].iter().any(|byte| *byte != 0) {
+        runtime::revert(Error::InvalidAmount);
+    }
+    U256::from_little_endian(&bytes[..32])
 }
 
-#[no_mangle]
-pub extern "C" fn balance_of() {
-    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+fn credit_tokens(owner: &Key, amount: U256) {
     let dict = get_dict(DICT_BALANCES);
-    let balance: U256 = storage::dictionary_get(dict, &make_key(&owner))
+    let balance: U256 = storage::dictionary_get(dict, &make_key(owner))
         .unwrap_or_revert()
         .unwrap_or(U256::zero());
-    runtime::ret(CLValue::from_t(balance).unwrap_or_revert());
+    storage::dictionary_put(dict, &make_key(owner), balance + amount);
 }
 
-#[no_mangle]
-pub extern "C" fn transfer() {
-    let recipient: Key = runtime::get_named_arg(ARG_RECIPIENT);
-    let amount: U256 = runtime::get_named_arg(ARG_AMOUNT);
-    let caller = Key::from(runtime::get_caller());
-    
+fn token_balance(owner: &Key) -> U256 {
     let dict = get_dict(DICT_BALANCES);
-    let from_bal: U256 = storage::dictionary_get(dict, &make_key(&caller))
+    storage::dictionary_get(dict, &make_key(owner))
         .unwrap_or_revert()
-        .unwrap_or(U256::zero());
-    
-    if from_bal < amount {
+        .unwrap_or(U256::zero())
+}
+
+fn debit_tokens(owner: &Key, amount: U256) {
+    let dict = get_dict(DICT_BALANCES);
+    let balance = token_balance(owner);
+
+    if balance < amount {
         runtime::revert(Error::InsufficientBalance);
     }
-    
-    let to_bal: U256 = storage::dictionary_get(dict, &make_key(&recipient))
-        .unwrap_or_revert()
-        .unwrap_or(U256::zero());
-    
-    storage::dictionary_put(dict, &make_key(&caller), from_bal - amount);
-    storage::dictionary_put(dict, &make_key(&recipient), to_bal + amount);
-}
 
-#[no_mangle]
-pub extern "C" fn approve() {
-    let spender: Key = runtime::get_named_arg(ARG_SPENDER);
-    let amount: U256 = runtime::get_named_arg(ARG_AMOUNT);
-    let caller = Key::from(runtime::get_caller());
-    
-    let dict = get_dict(DICT_ALLOWANCES);
-    let key = format!("{}_{}", make_key(&caller), make_key(&spender));
-    storage::dictionary_put(dict, &key, amount);
+    storage::dictionary_put(dict, &make_key(owner), balance - amount);
 }
 
-#[no_mangle]
-pub extern "C" fn allowance() {
-    let owner: Key = runtime::get_named_arg(ARG_OWNER);
-    let spender: Key = runtime::get_named_arg(ARG_SPENDER);
-    
-    let dict = get_dict(DICT_ALLOWANCES);
-    let key = format!("{}_{}", make_key(&owner), make_key(&spender));
-    let val: U256 = storage::dictionary_get(dict, &key)
-        .unwrap_or_revert()
-        .unwrap_or(U256::zero());
-    runtime::ret(CLValue::from_t(val).unwrap_or_revert());
+fn bps(value: U512, bps: u32) -> U512 {
+    value * U512::from(bps) / U512::from(BPS_DENOMINATOR)
 }
 
-// Staking functions
-#[no_mangle]
-pub extern "C" fn stake() {
-    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
-    let caller = Key::from(runtime::get_caller());
-    
-    let dict = get_dict(DICT_STAKERS);
-    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
+// Vault balances double as the CSPR account that trading collateral is drawn from
+fn credit_vault(owner: &Key, amount: U512) {
+    let dict = get_dict(DICT_VAULT);
+    let current: U512 = storage::dictionary_get(dict, &make_key(owner))
         .unwrap_or_revert()
         .unwrap_or(U512::zero());
-    
-    let new_stake = current + amount;
-    storage::dictionary_put(dict, &make_key(&caller), new_stake);
-    
-    let total: U512 = get_key(KEY_TOTAL_STAKED);
-    set_key(KEY_TOTAL_STAKED, total + amount);
+    storage::dictionary_put(dict, &make_key(owner), current + amount);
+
+    let total: U512 = get_key(KEY_VAULT_TOTAL);
+    set_key(KEY_VAULT_TOTAL, total + amount);
 }
 
-#[no_mangle]
-pub extern "C" fn unstake() {
-    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
-    let caller = Key::from(runtime::get_caller());
-    
-    let dict = get_dict(DICT_STAKERS);
-    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
+fn vault_balance(owner: &Key) -> U512 {
+    let dict = get_dict(DICT_VAULT);
+    storage::dictionary_get(dict, &make_key(owner))
         .unwrap_or_revert()
-        .unwrap_or(U512::zero());
-    
+        .unwrap_or(U512::zero())
+}
+
+fn debit_vault(owner: &Key, amount: U512) {
+    let dict = get_dict(DICT_VAULT);
+    let current = vault_balance(owner);
+
     if current < amount {
         runtime::revert(Error::InsufficientBalance);
     }
-    
-    storage::dictionary_put(dict, &make_key(&caller), current - amount);
-    
-    let total: U512 = get_key(KEY_TOTAL_STAKED);
-    set_key(KEY_TOTAL_STAKED, total - amount);
+
+    storage::dictionary_put(dict, &make_key(owner), current - amount);
+
+    let total: U512 = get_key(KEY_VAULT_TOTAL);
+    set_key(KEY_VAULT_TOTAL, total - amount);
 }
 
-#[no_mangle]
-pub extern "C" fn get_stake() {
-    let owner: Key = runtime::get_named_arg(ARG_OWNER);
-    let dict = get_dict(DICT_STAKERS);
-    let stake: U512 = storage::dictionary_get(dict, &make_key(&owner))
-        .unwrap_or_revert()
-        .unwrap_or(U512::zero());
-    runtime::ret(CLValue::from_t(stake).unwrap_or_revert());
+// Leveraged position stored under `owner_positionid` in the positions dictionary.
+// `size` is the notional in collateral units at `entry_price`; a closed position
+// has zero size and collateral.
+#[derive(Clone)]
+struct Position {
+    id: u64,
+    owner: Key,
+    market_id: u64,
+    side: u8,
+    collateral: U512,
+    size: U512,
+    entry_price: U512,
+    leverage: u32,
+    entry_funding_index: i64,
+    opened_at: u64,
 }
 
-// Trading functions
-#[no_mangle]
-pub extern "C" fn open_position() {
-    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
-    let leverage: u32 = runtime::get_named_arg::<u32>("leverage");
-    let caller = Key::from(runtime::get_caller());
-    
+impl CLTyped for Position {
+    fn cl_type() -> CLType {
+        CLType::Any
+    }
+}
+
+impl ToBytes for Position {
+    fn to_bytes(&self) -> Result<Vec<u8>, bytesrepr::Error> {
+        let mut result = bytesrepr::allocate_buffer(self)?;
+        result.extend(self.id.to_bytes()?);
+        result.extend(self.owner.to_bytes()?);
+        result.extend(self.market_id.to_bytes()?);
+        result.extend(self.side.to_bytes()?);
+        result.extend(self.collateral.to_bytes()?);
+        result.extend(self.size.to_bytes()?);
+        result.extend(self.entry_price.to_bytes()?);
+        result.extend(self.leverage.to_bytes()?);
+        result.extend(self.entry_funding_index.to_bytes()?);
+        result.extend(self.opened_at.to_bytes()?);
+        Ok(result)
+    }
+
+    fn serialized_length(&self) -> usize {
+        self.id.serialized_length()
+            + self.owner.serialized_length()
+            + self.market_id.serialized_length()
+            + self.side.serialized_length()
+            + self.collateral.serialized_length()
+            + self.size.serialized_length()
+            + self.entry_price.serialized_length()
+            + self.leverage.serialized_length()
+            + self.entry_funding_index.serialized_length()
+            + self.opened_at.serialized_length()
+    }
+}
+
+impl FromBytes for Position {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), bytesrepr::Error> {
+        let (id, rem) = u64::from_bytes(bytes)?;
+        let (owner, rem) = Key::from_bytes(rem)?;
+        let (market_id, rem) = u64::from_bytes(rem)?;
+        let (side, rem) = u8::from_bytes(rem)?;
+        let (collateral, rem) = U512::from_bytes(rem)?;
+        let (size, rem) = U512::from_bytes(rem)?;
+        let (entry_price, rem) = U512::from_bytes(rem)?;
+        let (leverage, rem) = u32::from_bytes(rem)?;
+        let (entry_funding_index, rem) = i64::from_bytes(rem)?;
+        let (opened_at, rem) = u64::from_bytes(rem)?;
+        Ok((
+            Position {
+                id,
+                owner,
+                market_id,
+                side,
+                collateral,
+                size,
+                entry_price,
+                leverage,
+                entry_funding_index,
+                opened_at,
+            },
+            rem,
+        ))
+    }
+}
+
+fn position_key(owner: &Key, position_id: u64) -> String {
+    format!("{}_{}", make_key(owner), position_id)
+}
+
+fn read_position(owner: &Key, position_id: u64) -> Position {
     let dict = get_dict(DICT_POSITIONS);
-    let position_id: u64 = runtime::get_blocktime().into();
-    let key = format!("{}_{}", make_key(&caller), position_id);
-    let size = amount * U512::from(leverage);
-    storage::dictionary_put(dict, &key, size);
+    let position: Position = storage::dictionary_get(dict, &position_key(owner, position_id))
+        .unwrap_or_revert()
+        .unwrap_or_revert_with(Error::PositionNotFound);
+    if position.size.is_zero() {
+        runtime::revert(Error::PositionNotFound);
+    }
+    position
 }
 
-#[no_mangle]
-pub extern "C" fn close_position() {
-    let position_id: u64 = runtime::get_named_arg("position_id");
-    let caller = Key::from(runtime::get_caller());
-    
+fn write_position(position: &Position) {
     let dict = get_dict(DICT_POSITIONS);
-    let key = format!("{}_{}", make_key(&caller), position_id);
-    storage::dictionary_put(dict, &key, U512::zero());
+    let key = position_key(&position.owner, position.id);
+    storage::dictionary_put(dict, &key, position.clone());
 }
 
-// Vault functions
-#[no_mangle]
-pub extern "C" fn vault_deposit() {
-    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
-    let caller = Key::from(runtime::get_caller());
-    
-    let dict = get_dict(DICT_VAULT);
-    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
-        .unwrap_or_revert()
-        .unwrap_or(U512::zero());
-    
-    storage::dictionary_put(dict, &make_key(&caller), current + amount);
-    
-    let total: U512 = get_key(KEY_VAULT_TOTAL);
-    set_key(KEY_VAULT_TOTAL, total + amount);
+// Admin-registered trading pair. Prices are pushed by the market's `oracle` key.
+// `funding_rate_bps` is the hourly funding rate paid by the heavier side when open
+// interest is entirely one-sided. Trades that leave their own side heavier are filled
+// `price_impact_bps` worse than the oracle price, scaled by the resulting skew.
+// An open interest cap of zero means the side is uncapped.
+#[derive(Clone)]
+struct Market {
+    id: u64,
+    base_symbol: String,
+    quote_symbol: String,
+    oracle: Key,
+    max_leverage: u32,
+    initial_margin_bps: u32,
+    maintenance_margin_bps: u32,
+    liquidation_penalty_bps: u32,
+    funding_rate_bps: u32,
+    maker_fee_bps: u32,
+    taker_fee_bps: u32,
+    price_impact_bps: u32,
+    max_long_open_interest: U512,
+    max_short_open_interest: U512,
+    enabled: bool,
 }
 
-#[no_mangle]
-pub extern "C" fn vault_withdraw() {
-    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
-    let caller = Key::from(runtime::get_caller());
-    
-    let dict = get_dict(DICT_VAULT);
-    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
-        .unwrap_or_revert()
-        .unwrap_or(U512::zero());
-    
-    if current < amount {
-        runtime::revert(Error::InsufficientBalance);
+impl CLTyped for Market {
+    fn cl_type() -> CLType {
+        CLType::Any
     }
-    
-    storage::dictionary_put(dict, &make_key(&caller), current - amount);
-    
-    let total: U512 = get_key(KEY_VAULT_TOTAL);
-    set_key(KEY_VAULT_TOTAL, total - amount);
 }
-// Liquid Staking Derivatives functions
-#[no_mangle]
-pub extern "C" fn stake_for_liquid() {
-    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
-    let caller = Key::from(runtime::get_caller());
-    
-    // Get current ratio (stCSPR per CSPR)
-    let ratio: U512 = get_key(KEY_LIQUID_STAKING_RATIO);
-    let stcspr_amount = if ratio == U512::zero() {
-        amount
-    } else {
-        amount * U512::from(1000000u64) / ratio
-    };
-    
-    // Update liquid staker balance
-    let dict = get_dict(DICT_LIQUID_STAKERS);
-    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
+
+impl ToBytes for Market {
+    fn to_bytes(&self) -> Result<Vec<u8>, bytesrepr::Error> {
+        let mut result = bytesrepr::allocate_buffer(self)?;
+        result.extend(self.id.to_bytes()?);
+        result.extend(self.base_symbol.to_bytes()?);
+        result.extend(self.quote_symbol.to_bytes()?);
+        result.extend(self.oracle.to_bytes()?);
+        result.extend(self.max_leverage.to_bytes()?);
+        result.extend(self.initial_margin_bps.to_bytes()?);
+        result.extend(self.maintenance_margin_bps.to_bytes()?);
+        result.extend(self.liquidation_penalty_bps.to_bytes()?);
+        result.extend(self.funding_rate_bps.to_bytes()?);
+        result.extend(self.maker_fee_bps.to_bytes()?);
+        result.extend(self.taker_fee_bps.to_bytes()?);
+        result.extend(self.price_impact_bps.to_bytes()?);
+        result.extend(self.max_long_open_interest.to_bytes()?);
+        result.extend(self.max_short_open_interest.to_bytes()?);
+        result.extend(self.enabled.to_bytes()?);
+        Ok(result)
+    }
+
+    fn serialized_length(&self) -> usize {
+        self.id.serialized_length()
+            + self.base_symbol.serialized_length()
+            + self.quote_symbol.serialized_length()
+            + self.oracle.serialized_length()
+            + self.max_leverage.serialized_length()
+            + self.initial_margin_bps.serialized_length()
+            + self.maintenance_margin_bps.serialized_length()
+            + self.liquidation_penalty_bps.serialized_length()
+            + self.funding_rate_bps.serialized_length()
+            + self.maker_fee_bps.serialized_length()
+            + self.taker_fee_bps.serialized_length()
+            + self.price_impact_bps.serialized_length()
+            + self.max_long_open_interest.serialized_length()
+            + self.max_short_open_interest.serialized_length()
+            + self.enabled.serialized_length()
+    }
+}
+
+impl FromBytes for Market {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), bytesrepr::Error> {
+        let (id, rem) = u64::from_bytes(bytes)?;
+        let (base_symbol, rem) = String::from_bytes(rem)?;
+        let (quote_symbol, rem) = String::from_bytes(rem)?;
+        let (oracle, rem) = Key::from_bytes(rem)?;
+        let (max_leverage, rem) = u32::from_bytes(rem)?;
+        let (initial_margin_bps, rem) = u32::from_bytes(rem)?;
+        let (maintenance_margin_bps, rem) = u32::from_bytes(rem)?;
+        let (liquidation_penalty_bps, rem) = u32::from_bytes(rem)?;
+        let (funding_rate_bps, rem) = u32::from_bytes(rem)?;
+        let (maker_fee_bps, rem) = u32::from_bytes(rem)?;
+        let (taker_fee_bps, rem) = u32::from_bytes(rem)?;
+        let (price_impact_bps, rem) = u32::from_bytes(rem)?;
+        let (max_long_open_interest, rem) = U512::from_bytes(rem)?;
+        let (max_short_open_interest, rem) = U512::from_bytes(rem)?;
+        let (enabled, rem) = bool::from_bytes(rem)?;
+        Ok((
+            Market {
+                id,
+                base_symbol,
+                quote_symbol,
+                oracle,
+                max_leverage,
+                initial_margin_bps,
+                maintenance_margin_bps,
+                liquidation_penalty_bps,
+                funding_rate_bps,
+                maker_fee_bps,
+                taker_fee_bps,
+                price_impact_bps,
+                max_long_open_interest,
+                max_short_open_interest,
+                enabled,
+            },
+            rem,
+        ))
+    }
+}
+
+// Mutable per-market state, kept apart from the admin configuration.
+// A positive `funding_index` means longs have paid shorts since listing.
+#[derive(Clone)]
+struct MarketState {
+    price: U512,
+    long_open_interest: U512,
+    short_open_interest: U512,
+    funding_index: i64,
+    last_funding_time: u64,
+}
+
+impl MarketState {
+    fn new(now: u64) -> Self {
+        MarketState {
+            price: U512::zero(),
+            long_open_interest: U512::zero(),
+            short_open_interest: U512::zero(),
+            funding_index: 0,
+            last_funding_time: now,
+        }
+    }
+}
+
+impl CLTyped for MarketState {
+    fn cl_type() -> CLType {
+        CLType::Any
+    }
+}
+
+impl ToBytes for MarketState {
+    fn to_bytes(&self) -> Result<Vec<u8>, bytesrepr::Error> {
+        let mut result = bytesrepr::allocate_buffer(self)?;
+        result.extend(self.price.to_bytes()?);
+        result.extend(self.long_open_interest.to_bytes()?);
+        result.extend(self.short_open_interest.to_bytes()?);
+        result.extend(self.funding_index.to_bytes()?);
+        result.extend(self.last_funding_time.to_bytes()?);
+        Ok(result)
+    }
+
+    fn serialized_length(&self) -> usize {
+        self.price.serialized_length()
+            + self.long_open_interest.serialized_length()
+            + self.short_open_interest.serialized_length()
+            + self.funding_index.serialized_length()
+            + self.last_funding_time.serialized_length()
+    }
+}
+
+impl FromBytes for MarketState {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), bytesrepr::Error> {
+        let (price, rem) = U512::from_bytes(bytes)?;
+        let (long_open_interest, rem) = U512::from_bytes(rem)?;
+        let (short_open_interest, rem) = U512::from_bytes(rem)?;
+        let (funding_index, rem) = i64::from_bytes(rem)?;
+        let (last_funding_time, rem) = u64::from_bytes(rem)?;
+        Ok((
+            MarketState {
+                price,
+                long_open_interest,
+                short_open_interest,
+                funding_index,
+                last_funding_time,
+            },
+            rem,
+        ))
+    }
+}
+
+fn read_market(market_id: u64) -> Market {
+    let dict = get_dict(DICT_MARKETS);
+    storage::dictionary_get(dict, &format!("{}", market_id))
         .unwrap_or_revert()
-        .unwrap_or(U512::zero());
-    storage::dictionary_put(dict, &make_key(&caller), current + amount);
-    
-    // Mint stCSPR tokens
-    let stcspr_dict = get_dict(DICT_STCSPR_BALANCES);
-    let stcspr_balance: U512 = storage::dictionary_get(stcspr_dict, &make_key(&caller))
+        .unwrap_or_revert_with(Error::MarketNotFound)
+}
+
+fn write_market(market: &Market) {
+    let dict = get_dict(DICT_MARKETS);
+    storage::dictionary_put(dict, &format!("{}", market.id), market.clone());
+}
+
+fn read_market_state(market_id: u64) -> MarketState {
+    let dict = get_dict(DICT_MARKET_STATE);
+    storage::dictionary_get(dict, &format!("{}", market_id))
         .unwrap_or_revert()
-        .unwrap_or(U512::zero());
-    storage::dictionary_put(stcspr_dict, &make_key(&caller), stcspr_balance + stcspr_amount);
-    
-    // Update totals
-    let total_liquid: U512 = get_key(KEY_TOTAL_LIQUID_STAKED);
-    set_key(KEY_TOTAL_LIQUID_STAKED, total_liquid + amount);
-    
-    let total_staked: U512 = get_key(KEY_TOTAL_STAKED);
-    set_key(KEY_TOTAL_STAKED, total_staked + amount);
+        .unwrap_or_revert_with(Error::MarketNotFound)
 }
 
-#[no_mangle]
-pub extern "C" fn unstake_liquid() {
-    let stcspr_amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
-    let caller = Key::from(runtime::get_caller());
-    
-    // Get current ratio
-    let ratio: U512 = get_key(KEY_LIQUID_STAKING_RATIO);
-    let cspr_amount = if ratio == U512::zero() {
-        stcspr_amount
+fn write_market_state(market_id: u64, state: &MarketState) {
+    let dict = get_dict(DICT_MARKET_STATE);
+    storage::dictionary_put(dict, &format!("{}", market_id), state.clone());
+}
+
+// Reads the risk parameters shared by `add_market` and `update_market`
+fn market_from_args(id: u64, base_symbol: String, quote_symbol: String, enabled: bool) -> Market {
+    let market = Market {
+        id,
+        base_symbol,
+        quote_symbol,
+        oracle: runtime::get_named_arg(ARG_ORACLE),
+        max_leverage: runtime::get_named_arg(ARG_MAX_LEVERAGE),
+        initial_margin_bps: runtime::get_named_arg(ARG_INITIAL_MARGIN_BPS),
+        maintenance_margin_bps: runtime::get_named_arg(ARG_MAINTENANCE_MARGIN_BPS),
+        liquidation_penalty_bps: runtime::get_named_arg(ARG_LIQUIDATION_PENALTY_BPS),
+        funding_rate_bps: runtime::get_named_arg(ARG_FUNDING_RATE_BPS),
+        maker_fee_bps: runtime::get_named_arg(ARG_MAKER_FEE_BPS),
+        taker_fee_bps: runtime::get_named_arg(ARG_TAKER_FEE_BPS),
+        price_impact_bps: runtime::get_named_arg(ARG_PRICE_IMPACT_BPS),
+        max_long_open_interest: runtime::get_named_arg(ARG_MAX_LONG_OPEN_INTEREST),
+        max_short_open_interest: runtime::get_named_arg(ARG_MAX_SHORT_OPEN_INTEREST),
+        enabled,
+    };
+
+    let max_leverage: u32 = get_key(KEY_MAX_LEVERAGE);
+    if market.max_leverage == 0
+        || market.max_leverage > max_leverage
+        || market.maintenance_margin_bps == 0
+        || market.maintenance_margin_bps >= market.initial_margin_bps
+        || u64::from(market.max_leverage) * u64::from(market.initial_margin_bps) > BPS_DENOMINATOR
+        || market.liquidation_penalty_bps > market.maintenance_margin_bps
+        || u64::from(market.funding_rate_bps) > BPS_DENOMINATOR
+        || u64::from(market.maker_fee_bps) > BPS_DENOMINATOR
+        || u64::from(market.taker_fee_bps) > BPS_DENOMINATOR
+        || u64::from(market.price_impact_bps) >= BPS_DENOMINATOR
+    {
+        runtime::revert(Error::InvalidMarginParams);
+    }
+    market
+}
+
+fn market_price(market_id: u64) -> U512 {
+    let price = read_market_state(market_id).price;
+    if price.is_zero() {
+        runtime::revert(Error::PriceNotSet);
+    }
+    price
+}
+
+// Returns (equity, shortfall) of a position at `price`. Equity is collateral plus
+// unrealized PnL floored at zero; shortfall is the loss not covered by collateral.
+fn position_equity(position: &Position, price: U512) -> (U512, U512) {
+    let price_move = if price >= position.entry_price {
+        price - position.entry_price
     } else {
-        stcspr_amount * ratio / U512::from(1000000u64)
+        position.entry_price - price
     };
-    
-    // Burn stCSPR tokens
-    let stcspr_dict = get_dict(DICT_STCSPR_BALANCES);
-    let stcspr_balance: U512 = storage::dictionary_get(stcspr_dict, &make_key(&caller))
+    let pnl = position.size * price_move / position.entry_price;
+    let in_profit = (position.side == SIDE_LONG) == (price >= position.entry_price);
+
+    if in_profit {
+        (position.collateral + pnl, U512::zero())
+    } else if pnl > position.collateral {
+        (U512::zero(), pnl - position.collateral)
+    } else {
+        (position.collateral - pnl, U512::zero())
+    }
+}
+
+// Oracle price adjusted for the open interest skew the trade leaves behind. Opening a
+// long or closing a short buys; opening a short or closing a long sells.
+fn execution_price(
+    market: &Market,
+    state: &MarketState,
+    side: u8,
+    size: U512,
+    opening: bool,
+) -> U512 {
+    let price = market_price(market.id);
+    let mut long = state.long_open_interest;
+    let mut short = state.short_open_interest;
+    match (side == SIDE_LONG, opening) {
+        (true, true) => long += size,
+        (true, false) => long = long.saturating_sub(size),
+        (false, true) => short += size,
+        (false, false) => short = short.saturating_sub(size),
+    }
+
+    let is_buy = (side == SIDE_LONG) == opening;
+    let total = long + short;
+    if total.is_zero() || is_buy != (long > short) {
+        return price;
+    }
+
+    let skew = if long > short { long - short } else { short - long };
+    let impact = price * U512::from(market.price_impact_bps) * skew
+        / (U512::from(BPS_DENOMINATOR) * total);
+    if is_buy {
+        price + impact
+    } else {
+        price - impact
+    }
+}
+
+// Reverts when the deadline has passed or the fill is worse than `acceptable_price`
+fn check_execution(side: u8, opening: bool, price: U512) {
+    let acceptable_price: U512 = runtime::get_named_arg(ARG_ACCEPTABLE_PRICE);
+    let deadline: u64 = runtime::get_named_arg(ARG_DEADLINE);
+
+    let now: u64 = runtime::get_blocktime().into();
+    if now > deadline {
+        runtime::revert(Error::DeadlineExpired);
+    }
+
+    let is_buy = (side == SIDE_LONG) == opening;
+    if (is_buy && price > acceptable_price) || (!is_buy && price < acceptable_price) {
+        runtime::revert(Error::SlippageExceeded);
+    }
+}
+
+fn maintenance_margin(market: &Market, position: &Position) -> U512 {
+    bps(position.size, market.maintenance_margin_bps)
+}
+
+fn is_liquidatable(market: &Market, position: &Position, price: U512) -> bool {
+    let (equity, _) = position_equity(position, price);
+    equity < maintenance_margin(market, position)
+}
+
+fn require_initial_margin(market: &Market, position: &Position, price: U512) {
+    let (equity, _) = position_equity(position, price);
+    if equity < bps(position.size, market.initial_margin_bps) {
+        runtime::revert(Error::InsufficientMargin);
+    }
+}
+
+// Splits `size` of notional off the position together with a proportional share of
+// its collateral and returns that slice.
+fn take_slice(position: &mut Position, size: U512) -> Position {
+    let collateral = position.collateral * size / position.size;
+    let mut slice = position.clone();
+    slice.collateral = collateral;
+    slice.size = size;
+
+    position.collateral -= collateral;
+    position.size -= size;
+    slice
+}
+
+// Signed PnL of a closed slice as (is_profit, magnitude)
+fn realized_pnl(slice: &Position, equity: U512, shortfall: U512) -> (bool, U512) {
+    if equity >= slice.collateral {
+        (true, equity - slice.collateral)
+    } else {
+        (false, slice.collateral - equity + shortfall)
+    }
+}
+
+fn effective_leverage(position: &Position) -> u32 {
+    if position.collateral.is_zero() {
+        return u32::MAX;
+    }
+    let leverage = position.size / position.collateral;
+    core::cmp::min(leverage, U512::from(u32::MAX)).as_u32()
+}
+
+fn increase_open_interest(market: &Market, side: u8, size: U512) {
+    let mut state = read_market_state(market.id);
+    let (open_interest, cap) = if side == SIDE_LONG {
+        state.long_open_interest += size;
+        (state.long_open_interest, market.max_long_open_interest)
+    } else {
+        state.short_open_interest += size;
+        (state.short_open_interest, market.max_short_open_interest)
+    };
+
+    if !cap.is_zero() && open_interest > cap {
+        runtime::revert(Error::OpenInterestCapExceeded);
+    }
+    write_market_state(market.id, &state);
+}
+
+// Tracks open notional (at entry) and open position count per account across all
+// markets. Limits of zero are disabled.
+fn increase_account_exposure(owner: &Key, size: U512, new_position: bool) {
+    let notional_dict = get_dict(DICT_ACCOUNT_NOTIONAL);
+    let notional: U512 = storage::dictionary_get(notional_dict, &make_key(owner))
         .unwrap_or_revert()
         .unwrap_or(U512::zero());
-    
-    if stcspr_balance < stcspr_amount {
-        runtime::revert(ApiError::from(Error::InsufficientBalance));
+
+    let max_notional: U512 = get_key(KEY_MAX_ACCOUNT_NOTIONAL);
+    if !max_notional.is_zero() && notional + size > max_notional {
+        runtime::revert(Error::AccountNotionalLimitExceeded);
     }
-    
-    storage::dictionary_put(stcspr_dict, &make_key(&caller), stcspr_balance - stcspr_amount);
-    
-    // Update liquid staker balance
-    let dict = get_dict(DICT_LIQUID_STAKERS);
-    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
+    storage::dictionary_put(notional_dict, &make_key(owner), notional + size);
+
+    if new_position {
+        let count_dict = get_dict(DICT_ACCOUNT_POSITIONS);
+        let count: u32 = storage::dictionary_get(count_dict, &make_key(owner))
+            .unwrap_or_revert()
+            .unwrap_or(0);
+
+        let max_positions: u32 = get_key(KEY_MAX_POSITIONS_PER_ACCOUNT);
+        if max_positions != 0 && count >= max_positions {
+            runtime::revert(Error::MaxPositionsExceeded);
+        }
+        storage::dictionary_put(count_dict, &make_key(owner), count + 1);
+    }
+}
+
+fn decrease_account_exposure(owner: &Key, size: U512, closed_position: bool) {
+    let notional_dict = get_dict(DICT_ACCOUNT_NOTIONAL);
+    let notional: U512 = storage::dictionary_get(notional_dict, &make_key(owner))
         .unwrap_or_revert()
         .unwrap_or(U512::zero());
-    storage::dictionary_put(dict, &make_key(&caller), current - cspr_amount);
-    
-    // Update totals
-    let total_liquid: U512 = get_key(KEY_TOTAL_LIQUID_STAKED);
-    set_key(KEY_TOTAL_LIQUID_STAKED, total_liquid - cspr_amount);
-    
-    let total_staked: U512 = get_key(KEY_TOTAL_STAKED);
-    set_key(KEY_TOTAL_STAKED, total_staked - cspr_amount);
+    storage::dictionary_put(notional_dict, &make_key(owner), notional.saturating_sub(size));
+
+    if closed_position {
+        let count_dict = get_dict(DICT_ACCOUNT_POSITIONS);
+        let count: u32 = storage::dictionary_get(count_dict, &make_key(owner))
+            .unwrap_or_revert()
+            .unwrap_or(0);
+        storage::dictionary_put(count_dict, &make_key(owner), count.saturating_sub(1));
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn get_liquid_stake_ratio() {
-    let ratio: U512 = get_key(KEY_LIQUID_STAKING_RATIO);
-    runtime::ret(CLValue::from_t(ratio).unwrap_or_revert());
+fn decrease_open_interest(market_id: u64, side: u8, size: U512) {
+    let mut state = read_market_state(market_id);
+    if side == SIDE_LONG {
+        state.long_open_interest = state.long_open_interest.saturating_sub(size);
+    } else {
+        state.short_open_interest = state.short_open_interest.saturating_sub(size);
+    }
+    write_market_state(market_id, &state);
 }
 
-#[no_mangle]
-pub extern "C" fn get_stcspr_balance() {
-    let owner: Key = runtime::get_named_arg(ARG_OWNER);
-    let dict = get_dict(DICT_STCSPR_BALANCES);
-    let balance: U512 = storage::dictionary_get(dict, &make_key(&owner))
+// Rolls the market funding index forward to the current block time. The rate scales
+// with open interest skew, so this must run before open interest changes.
+fn accrue_funding(market: &Market) -> MarketState {
+    let mut state = read_market_state(market.id);
+    let now: u64 = runtime::get_blocktime().into();
+    let elapsed = now.saturating_sub(state.last_funding_time);
+    state.last_funding_time = now;
+
+    let total = state.long_open_interest + state.short_open_interest;
+    if elapsed > 0 && !total.is_zero() {
+        let longs_pay = state.long_open_interest >= state.short_open_interest;
+        let skew = if longs_pay {
+            state.long_open_interest - state.short_open_interest
+        } else {
+            state.short_open_interest - state.long_open_interest
+        };
+        let rate = U512::from(market.funding_rate_bps) * U512::from(FUNDING_PRECISION)
+            / U512::from(BPS_DENOMINATOR);
+        let delta = rate * U512::from(elapsed) * skew / (U512::from(FUNDING_INTERVAL) * total);
+        let delta = core::cmp::min(delta, U512::from(i64::MAX as u64)).as_u64() as i64;
+        state.funding_index = if longs_pay {
+            state.funding_index.saturating_add(delta)
+        } else {
+            state.funding_index.saturating_sub(delta)
+        };
+    }
+
+    write_market_state(market.id, &state);
+    state
+}
+
+// Moves funding accrued since the position's last settlement into its collateral.
+// Funding owed beyond the remaining collateral is dropped with the collateral at zero.
+fn settle_funding(position: &mut Position, state: &MarketState) {
+    let delta = state.funding_index - position.entry_funding_index;
+    let pays = (position.side == SIDE_LONG) == (delta > 0);
+    let amount = position.size * U512::from(delta.unsigned_abs()) / U512::from(FUNDING_PRECISION);
+
+    position.collateral = if pays {
+        position.collateral.saturating_sub(amount)
+    } else {
+        position.collateral + amount
+    };
+    position.entry_funding_index = state.funding_index;
+
+    if !amount.is_zero() {
+        record_trade(position, TRADE_FUNDING, U512::zero(), state.price, amount, !pays);
+    }
+}
+
+// One entry of an account's append-only trade ledger. `amount` is the collateral
+// posted for opens and modifications, realized PnL for closes, liquidations and ADL,
+// and funding or fees; `credit` is set when the amount went to the trader rather than
+// being paid by them.
+#[derive(Clone)]
+struct TradeRecord {
+    kind: u8,
+    position_id: u64,
+    market_id: u64,
+    side: u8,
+    size: U512,
+    price: U512,
+    amount: U512,
+    credit: bool,
+    timestamp: u64,
+}
+
+impl CLTyped for TradeRecord {
+    fn cl_type() -> CLType {
+        CLType::Any
+    }
+}
+
+impl ToBytes for TradeRecord {
+    fn to_bytes(&self) -> Result<Vec<u8>, bytesrepr::Error> {
+        let mut result = bytesrepr::allocate_buffer(self)?;
+        result.extend(self.kind.to_bytes()?);
+        result.extend(self.position_id.to_bytes()?);
+        result.extend(self.market_id.to_bytes()?);
+        result.extend(self.side.to_bytes()?);
+        result.extend(self.size.to_bytes()?);
+        result.extend(self.price.to_bytes()?);
+        result.extend(self.amount.to_bytes()?);
+        result.extend(self.credit.to_bytes()?);
+        result.extend(self.timestamp.to_bytes()?);
+        Ok(result)
+    }
+
+    fn serialized_length(&self) -> usize {
+        self.kind.serialized_length()
+            + self.position_id.serialized_length()
+            + self.market_id.serialized_length()
+            + self.side.serialized_length()
+            + self.size.serialized_length()
+            + self.price.serialized_length()
+            + self.amount.serialized_length()
+            + self.credit.serialized_length()
+            + self.timestamp.serialized_length()
+    }
+}
+
+impl FromBytes for TradeRecord {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), bytesrepr::Error> {
+        let (kind, rem) = u8::from_bytes(bytes)?;
+        let (position_id, rem) = u64::from_bytes(rem)?;
+        let (market_id, rem) = u64::from_bytes(rem)?;
+        let (side, rem) = u8::from_bytes(rem)?;
+        let (size, rem) = U512::from_bytes(rem)?;
+        let (price, rem) = U512::from_bytes(rem)?;
+        let (amount, rem) = U512::from_bytes(rem)?;
+        let (credit, rem) = bool::from_bytes(rem)?;
+        let (timestamp, rem) = u64::from_bytes(rem)?;
+        Ok((
+            TradeRecord {
+                kind,
+                position_id,
+                market_id,
+                side,
+                size,
+                price,
+                amount,
+                credit,
+                timestamp,
+            },
+            rem,
+        ))
+    }
+}
+
+fn trade_count(owner: &Key) -> u64 {
+    let dict = get_dict(DICT_TRADE_COUNT);
+    storage::dictionary_get(dict, &make_key(owner))
         .unwrap_or_revert()
-        .unwrap_or(U512::zero());
-    runtime::ret(CLValue::from_t(balance).unwrap_or_revert());
+        .unwrap_or(0)
 }
 
-// Limit Orders & Stop Loss functions
-#[no_mangle]
-pub extern "C" fn create_limit_order() {
-    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
-    let price: U512 = runtime::get_named_arg(ARG_PRICE);
-    let order_type: u8 = runtime::get_named_arg(ARG_ORDER_TYPE); // 0=buy, 1=sell
-    let caller = Key::from(runtime::get_caller());
+fn record_trade(
+    position: &Position,
+    kind: u8,
+    size: U512,
+    price: U512,
+    amount: U512,
+    credit: bool,
+) {
+    if kind == TRADE_FEE && amount.is_zero() {
+        return;
+    }
+
+    let index = trade_count(&position.owner);
+    let record = TradeRecord {
+        kind,
+        position_id: position.id,
+        market_id: position.market_id,
+        side: position.side,
+        size,
+        price,
+        amount,
+        credit,
+        timestamp: runtime::get_blocktime().into(),
+    };
+
+    let dict = get_dict(DICT_TRADE_HISTORY);
+    storage::dictionary_put(dict, &format!("{}_{}", make_key(&position.owner), index), record);
+
+    let counts = get_dict(DICT_TRADE_COUNT);
+    storage::dictionary_put(counts, &make_key(&position.owner), index + 1);
+}
+
+// Splits a trading fee between the insurance fund and the treasury
+fn collect_fee(fee: U512) {
+    let share_bps: u32 = get_key(KEY_INSURANCE_FEE_SHARE_BPS);
+    let insurance_share = bps(fee, share_bps);
+    fund_insurance(insurance_share);
+
+    credit_treasury(fee - insurance_share);
+}
+
+fn fund_insurance(amount: U512) {
+    let insurance: U512 = get_key(KEY_INSURANCE_FUND);
+    set_key(KEY_INSURANCE_FUND, insurance + amount);
+}
+
+fn credit_treasury(amount: U512) {
+    let treasury: U512 = get_key(KEY_TREASURY);
+    set_key(KEY_TREASURY, treasury + amount);
+}
+
+// Spot trading volume is tracked per account in VOLUME_WINDOW_DAYS daily buckets
+// under `owner_slot`, holding (day, volume), next to a cached (total, last_day).
+fn volume_bucket(owner: &Key, slot: u64) -> (u64, U512) {
+    let dict = get_dict(DICT_VOLUME_BUCKETS);
+    storage::dictionary_get(dict, &format!("{}_{}", make_key(owner), slot))
+        .unwrap_or_revert()
+        .unwrap_or((0, U512::zero()))
+}
+
+fn volume_total(owner: &Key) -> (U512, u64) {
+    let dict = get_dict(DICT_VOLUME_TOTALS);
+    storage::dictionary_get(dict, &make_key(owner))
+        .unwrap_or_revert()
+        .unwrap_or((U512::zero(), 0))
+}
+
+// Volume over the VOLUME_WINDOW_DAYS days up to and including `today`: the cached
+// total minus the buckets that fell out of the window since it was last updated
+fn rolling_volume(owner: &Key, today: u64) -> U512 {
+    let (mut total, last_day) = volume_total(owner);
+    let first = last_day + 1;
+    let last = core::cmp::min(today, last_day + VOLUME_WINDOW_DAYS);
+    for day in first..=last {
+        let (bucket_day, volume) = volume_bucket(owner, day % VOLUME_WINDOW_DAYS);
+        let counted = bucket_day + VOLUME_WINDOW_DAYS > last_day;
+        if counted && bucket_day + VOLUME_WINDOW_DAYS <= today {
+            total -= volume;
+        }
+    }
+    total
+}
+
+fn record_volume(owner: &Key, notional: U512) {
+    let now: u64 = runtime::get_blocktime().into();
+    let today = now / DAY_MILLIS;
+    let total = rolling_volume(owner, today);
+
+    let slot = today % VOLUME_WINDOW_DAYS;
+    let (bucket_day, volume) = volume_bucket(owner, slot);
+    let volume = if bucket_day == today { volume } else { U512::zero() };
+    let buckets = get_dict(DICT_VOLUME_BUCKETS);
+    let key = format!("{}_{}", make_key(owner), slot);
+    storage::dictionary_put(buckets, &key, (today, volume + notional));
+
+    let totals = get_dict(DICT_VOLUME_TOTALS);
+    storage::dictionary_put(totals, &make_key(owner), (total + notional, today));
+}
+
+// Highest discount among the tiers whose threshold `value` reaches
+fn tier_discount(tiers: &[(U512, u32)], value: U512) -> u32 {
+    tiers
+        .iter()
+        .filter(|(threshold, _)| value >= *threshold)
+        .map(|(_, discount_bps)| *discount_bps)
+        .max()
+        .unwrap_or(0)
+}
+
+// Fee discount from the account's 30-day volume tier plus its CFLOW staking tier
+/// CFLOW the account has locked with `stake_for_fees`
+fn fee_stake(owner: &Key) -> U512 {
+    storage::dictionary_get(get_dict(DICT_FEE_STAKES), &make_key(owner))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero())
+}
+
+fn fee_discount_bps(owner: &Key) -> u32 {
+    let now: u64 = runtime::get_blocktime().into();
+    let volume = rolling_volume(owner, now / DAY_MILLIS);
+    let stake = fee_stake(owner);
+
+    let volume_tiers: Vec<(U512, u32)> = get_key(KEY_VOLUME_TIERS);
+    let staking_tiers: Vec<(U512, u32)> = get_key(KEY_STAKING_TIERS);
+    let discount = tier_discount(&volume_tiers, volume) + tier_discount(&staking_tiers, stake);
+    core::cmp::min(u64::from(discount), BPS_DENOMINATOR) as u32
+}
+
+// Fee on `notional` at `fee_bps` after the account's discounts. The notional counts
+// towards the account's volume from the next trade on.
+fn trading_fee(owner: &Key, notional: U512, fee_bps: u32) -> U512 {
+    let discount_bps = fee_discount_bps(owner);
+    record_volume(owner, notional);
+    let fee = bps(notional, fee_bps);
+    fee - bps(fee, discount_bps)
+}
+
+// (maker fee, taker fee, maker rebate) of the CFLOW/CSPR spot pair in bps
+fn spot_fee_schedule() -> (u32, u32, u32) {
+    (
+        get_key(KEY_SPOT_MAKER_FEE_BPS),
+        get_key(KEY_SPOT_TAKER_FEE_BPS),
+        get_key(KEY_SPOT_MAKER_REBATE_BPS),
+    )
+}
+
+// Reads and validates a tier list: ascending thresholds with matching discounts
+fn fee_tiers_from_args() -> Vec<(U512, u32)> {
+    let thresholds: Vec<U512> = runtime::get_named_arg(ARG_THRESHOLDS);
+    let discounts_bps: Vec<u32> = runtime::get_named_arg(ARG_DISCOUNTS_BPS);
+    if thresholds.len() != discounts_bps.len() || thresholds.len() as u64 > MAX_FEE_TIERS {
+        runtime::revert(Error::InvalidMarginParams);
+    }
+    for index in 0..thresholds.len() {
+        let ascending = index == 0 || thresholds[index] > thresholds[index - 1];
+        if !ascending || u64::from(discounts_bps[index]) > BPS_DENOMINATOR {
+            runtime::revert(Error::InvalidMarginParams);
+        }
+    }
+    thresholds.into_iter().zip(discounts_bps).collect()
+}
+
+// Open positions are indexed per market and side under `market_side_slot` so that
+// auto-deleveraging can rank them. Slots are kept dense by swap-removal.
+fn market_side_key(market_id: u64, side: u8) -> String {
+    format!("{}_{}", market_id, side)
+}
+
+fn market_position_count(market_id: u64, side: u8) -> u64 {
+    let dict = get_dict(DICT_MARKET_POSITION_COUNT);
+    storage::dictionary_get(dict, &market_side_key(market_id, side))
+        .unwrap_or_revert()
+        .unwrap_or(0)
+}
+
+fn market_position_at(market_id: u64, side: u8, slot: u64) -> (Key, u64) {
+    let dict = get_dict(DICT_MARKET_POSITIONS);
+    let key = format!("{}_{}", market_side_key(market_id, side), slot);
+    storage::dictionary_get(dict, &key)
+        .unwrap_or_revert()
+        .unwrap_or_revert_with(Error::PositionNotFound)
+}
+
+fn index_position(position: &Position) {
+    let count = market_position_count(position.market_id, position.side);
+    let side_key = market_side_key(position.market_id, position.side);
+
+    let dict = get_dict(DICT_MARKET_POSITIONS);
+    let entry = (position.owner, position.id);
+    storage::dictionary_put(dict, &format!("{}_{}", side_key, count), entry);
+
+    let slots = get_dict(DICT_POSITION_SLOTS);
+    storage::dictionary_put(slots, &position_key(&position.owner, position.id), count);
+
+    let counts = get_dict(DICT_MARKET_POSITION_COUNT);
+    storage::dictionary_put(counts, &side_key, count + 1);
+}
+
+fn unindex_position(position: &Position) {
+    let count = market_position_count(position.market_id, position.side);
+    let side_key = market_side_key(position.market_id, position.side);
+    let slots = get_dict(DICT_POSITION_SLOTS);
+    let slot: u64 = storage::dictionary_get(slots, &position_key(&position.owner, position.id))
+        .unwrap_or_revert()
+        .unwrap_or_revert_with(Error::PositionNotFound);
+
+    let last = count - 1;
+    if slot != last {
+        let (owner, id) = market_position_at(position.market_id, position.side, last);
+        let dict = get_dict(DICT_MARKET_POSITIONS);
+        storage::dictionary_put(dict, &format!("{}_{}", side_key, slot), (owner, id));
+        storage::dictionary_put(slots, &position_key(&owner, id), slot);
+    }
+
+    let counts = get_dict(DICT_MARKET_POSITION_COUNT);
+    storage::dictionary_put(counts, &side_key, last);
+}
+
+// Price at which the position's equity reaches zero
+fn bankruptcy_price(position: &Position) -> U512 {
+    let buffer = position.entry_price * position.collateral / position.size;
+    if position.side == SIDE_LONG {
+        position.entry_price.saturating_sub(buffer)
+    } else {
+        position.entry_price + buffer
+    }
+}
+
+// ADL priority: unrealized PnL as a fraction of collateral times effective leverage.
+// Positions that are not in profit score zero and are never deleveraged.
+fn adl_score(position: &Position, price: U512) -> U512 {
+    let (equity, _) = position_equity(position, price);
+    if equity <= position.collateral {
+        return U512::zero();
+    }
+    let collateral = core::cmp::max(position.collateral, U512::one());
+    (equity - position.collateral) * position.size * U512::from(ADL_SCORE_PRECISION)
+        / (collateral * collateral)
+}
+
+// Auto-deleveraging state of a market side: (next index slot to scan, bad debt still
+// to recover, bankruptcy price it is recovered at)
+fn adl_queue(market_id: u64, side: u8) -> (u64, U512, U512) {
+    let dict = get_dict(DICT_ADL_QUEUE);
+    storage::dictionary_get(dict, &market_side_key(market_id, side))
+        .unwrap_or_revert()
+        .unwrap_or((0, U512::zero(), U512::zero()))
+}
+
+fn set_adl_queue(market_id: u64, side: u8, queue: (u64, U512, U512)) {
+    let dict = get_dict(DICT_ADL_QUEUE);
+    storage::dictionary_put(dict, &market_side_key(market_id, side), queue);
+}
+
+// Index slots of the next ADL window on a side, starting at `cursor` and wrapping
+fn adl_window(market_id: u64, side: u8, cursor: u64) -> Vec<u64> {
+    let count = market_position_count(market_id, side);
+    let len = core::cmp::min(count, MAX_ADL_SCAN);
+    (0..len).map(|offset| (cursor + offset) % count).collect()
+}
+
+// Profitable positions of the next ADL window, highest score first. Windows rotate
+// through the side's index so every position is reached without a full scan.
+fn adl_candidates(market_id: u64, side: u8, cursor: u64, price: U512) -> Vec<(U512, Position)> {
+    let mut candidates: Vec<(U512, Position)> = adl_window(market_id, side, cursor)
+        .into_iter()
+        .map(|slot| {
+            let (owner, id) = market_position_at(market_id, side, slot);
+            let position = read_position(&owner, id);
+            (adl_score(&position, price), position)
+        })
+        .filter(|(score, _)| !score.is_zero())
+        .collect();
+    candidates.sort_by_key(|(score, _)| core::cmp::Reverse(*score));
+    candidates
+}
+
+// Reduces the highest-ranked profitable positions of the side's next ADL window at
+// `bankruptcy_price` instead of the mark price until `deficit` is recovered. What is
+// left is carried over to the next window, see `continue_adl`.
+fn auto_deleverage(
+    market: &Market,
+    state: &MarketState,
+    side: u8,
+    mut deficit: U512,
+    bankruptcy_price: U512,
+    price: U512,
+) -> U512 {
+    let (cursor, _, _) = adl_queue(market.id, side);
+    let scanned = adl_window(market.id, side, cursor).len() as u64;
+    let candidates = adl_candidates(market.id, side, cursor, price);
+    for (_, mut position) in candidates.into_iter().take(MAX_ADL_POSITIONS as usize) {
+        if deficit.is_zero() {
+            break;
+        }
+        settle_funding(&mut position, state);
+
+        // Profit forgone per unit of notional by filling at the bankruptcy price
+        let price_gap = if price > bankruptcy_price {
+            price - bankruptcy_price
+        } else {
+            bankruptcy_price - price
+        };
+        if price_gap.is_zero() {
+            break;
+        }
+        let needed = deficit * position.entry_price / price_gap + U512::one();
+        let size = core::cmp::min(needed, position.size);
+
+        let slice = take_slice(&mut position, size);
+        let (mark_equity, _) = position_equity(&slice, price);
+        let (equity, shortfall) = position_equity(&slice, bankruptcy_price);
+        deficit = deficit.saturating_sub(mark_equity.saturating_sub(equity));
+
+        let (profit, pnl) = realized_pnl(&slice, equity, shortfall);
+        record_trade(&slice, TRADE_ADL, size, bankruptcy_price, pnl, profit);
+        credit_vault(&position.owner, equity);
+        decrease_open_interest(market.id, position.side, size);
+        decrease_account_exposure(&position.owner, size, position.size.is_zero());
+
+        if position.size.is_zero() {
+            unindex_position(&position);
+            cancel_position_orders(&position);
+            position.collateral = U512::zero();
+        } else {
+            position.leverage = effective_leverage(&position);
+        }
+        write_position(&position);
+
+        emit_event(format!(
+            "auto_deleveraged market_id={} owner={} position_id={} size={}",
+            market.id,
+            make_key(&position.owner),
+            position.id,
+            size
+        ));
+    }
+    set_adl_queue(market.id, side, (cursor + scanned, deficit, bankruptcy_price));
+    deficit
+}
+
+// Covers a bankrupt position's shortfall from the insurance fund, falling back to
+// auto-deleveraging the opposing side when the fund is exhausted.
+fn absorb_bad_debt(
+    market: &Market,
+    state: &MarketState,
+    bankrupt: &Position,
+    shortfall: U512,
+    price: U512,
+) {
+    let uncovered = cover_bad_debt(market.id, shortfall);
+    if uncovered.is_zero() {
+        return;
+    }
+
+    let opposing_side = if bankrupt.side == SIDE_LONG {
+        SIDE_SHORT
+    } else {
+        SIDE_LONG
+    };
+    // Debt still pending from earlier events is recovered along with this one
+    let (_, pending, _) = adl_queue(market.id, opposing_side);
+    let residual = auto_deleverage(
+        market,
+        state,
+        opposing_side,
+        uncovered + pending,
+        bankruptcy_price(bankrupt),
+        price,
+    );
+    if !residual.is_zero() {
+        emit_event(format!(
+            "adl_shortfall market_id={} residual={}",
+            market.id, residual
+        ));
+    }
+}
+
+// Draws a position's shortfall from the insurance fund. Returns the part of the bad
+// debt the fund could not cover and emits an event when the fund runs dry.
+fn cover_bad_debt(market_id: u64, shortfall: U512) -> U512 {
+    let insurance: U512 = get_key(KEY_INSURANCE_FUND);
+    let covered = core::cmp::min(insurance, shortfall);
+    set_key(KEY_INSURANCE_FUND, insurance - covered);
+
+    let uncovered = shortfall - covered;
+    if !uncovered.is_zero() {
+        emit_event(format!(
+            "insurance_fund_depleted market_id={} uncovered={}",
+            market_id, uncovered
+        ));
+    }
+    uncovered
+}
+
+// Keeper registry entry. Keepers bond CSPR from their vault balance and stay active
+// while the bond covers the minimum, they have not asked to exit and the admin has
+// not suspended them. The counters feed `keeper_stats`; each of the `slash_count`
+// slashes is recorded in the keeper slashes dictionary.
+#[derive(Clone)]
+struct Keeper {
+    bond: U512,
+    suspended: bool,
+    unbonding_at: u64,
+    executions: u64,
+    liquidations: u64,
+    funding_updates: u64,
+    fees_earned: U512,
+    slashed: U512,
+    slash_count: u64,
+}
+
+impl CLTyped for Keeper {
+    fn cl_type() -> CLType {
+        CLType::Any
+    }
+}
+
+impl ToBytes for Keeper {
+    fn to_bytes(&self) -> Result<Vec<u8>, bytesrepr::Error> {
+        let mut result = bytesrepr::allocate_buffer(self)?;
+        result.extend(self.bond.to_bytes()?);
+        result.extend(self.suspended.to_bytes()?);
+        result.extend(self.unbonding_at.to_bytes()?);
+        result.extend(self.executions.to_bytes()?);
+        result.extend(self.liquidations.to_bytes()?);
+        result.extend(self.funding_updates.to_bytes()?);
+        result.extend(self.fees_earned.to_bytes()?);
+        result.extend(self.slashed.to_bytes()?);
+        result.extend(self.slash_count.to_bytes()?);
+        Ok(result)
+    }
+
+    fn serialized_length(&self) -> usize {
+        self.bond.serialized_length()
+            + self.suspended.serialized_length()
+            + self.unbonding_at.serialized_length()
+            + self.executions.serialized_length()
+            + self.liquidations.serialized_length()
+            + self.funding_updates.serialized_length()
+            + self.fees_earned.serialized_length()
+            + self.slashed.serialized_length()
+            + self.slash_count.serialized_length()
+    }
+}
+
+impl FromBytes for Keeper {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), bytesrepr::Error> {
+        let (bond, rem) = U512::from_bytes(bytes)?;
+        let (suspended, rem) = bool::from_bytes(rem)?;
+        let (unbonding_at, rem) = u64::from_bytes(rem)?;
+        let (executions, rem) = u64::from_bytes(rem)?;
+        let (liquidations, rem) = u64::from_bytes(rem)?;
+        let (funding_updates, rem) = u64::from_bytes(rem)?;
+        let (fees_earned, rem) = U512::from_bytes(rem)?;
+        let (slashed, rem) = U512::from_bytes(rem)?;
+        let (slash_count, rem) = u64::from_bytes(rem)?;
+        Ok((
+            Keeper {
+                bond,
+                suspended,
+                unbonding_at,
+                executions,
+                liquidations,
+                funding_updates,
+                fees_earned,
+                slashed,
+                slash_count,
+            },
+            rem,
+        ))
+    }
+}
+
+fn read_keeper(keeper: &Key) -> Keeper {
+    let dict = get_dict(DICT_KEEPERS);
+    storage::dictionary_get(dict, &make_key(keeper))
+        .unwrap_or_revert()
+        .unwrap_or(Keeper {
+            bond: U512::zero(),
+            suspended: false,
+            unbonding_at: 0,
+            executions: 0,
+            liquidations: 0,
+            funding_updates: 0,
+            fees_earned: U512::zero(),
+            slashed: U512::zero(),
+            slash_count: 0,
+        })
+}
+
+fn write_keeper(keeper: &Key, record: &Keeper) {
+    let dict = get_dict(DICT_KEEPERS);
+    storage::dictionary_put(dict, &make_key(keeper), record.clone());
+}
+
+fn is_active_keeper(record: &Keeper) -> bool {
+    let min_bond: U512 = get_key(KEY_MIN_KEEPER_BOND);
+    !record.suspended
+        && record.unbonding_at == 0
+        && !record.bond.is_zero()
+        && record.bond >= min_bond
+}
+
+// Order stored under its id in the orders dictionary. `price` is the limit price of
+// limit orders and `trigger_price` the activation price of stop-loss and take-profit
+// orders, which close `amount` of notional (zero for all) of position `position_id`
+// and take the position's side. Trailing stops keep the best oracle price seen in
+// `price` and trail it by `trail_amount` or `trail_bps`. Cancelling or executing an
+// order also cancels its `linked_order_id` (one-cancels-the-other). Recurring orders
+// trade `amount` of the escrowed asset in `slice_amount` slices of `asset`, one per
+// `interval`, while the price is within `min_price` and `max_price` (zero for none).
+// `fee_escrow` holds the CSPR still reserved to pay keepers `execution_fee` per
+// execution. `escrow` is what is still locked for the order: CSPR for buys, CFLOW for
+// sells. `expires_at` is the block time a good-till-time order stops being fillable,
+// zero for none.
+#[derive(Clone)]
+struct Order {
+    id: u64,
+    owner: Key,
+    market_id: u64,
+    position_id: u64,
+    side: u8,
+    kind: u8,
+    amount: U512,
+    price: U512,
+    trigger_price: U512,
+    status: u8,
+    created_at: u64,
+    updated_at: u64,
+    filled_amount: U512,
+    escrow: U512,
+    time_in_force: u8,
+    expires_at: u64,
+    trail_amount: U512,
+    trail_bps: u32,
+    linked_order_id: u64,
+    asset: u8,
+    slice_amount: U512,
+    interval: u64,
+    next_execution_at: u64,
+    min_price: U512,
+    max_price: U512,
+    execution_fee: U512,
+    fee_escrow: U512,
+}
+
+impl CLTyped for Order {
+    fn cl_type() -> CLType {
+        CLType::Any
+    }
+}
+
+impl ToBytes for Order {
+    fn to_bytes(&self) -> Result<Vec<u8>, bytesrepr::Error> {
+        let mut result = bytesrepr::allocate_buffer(self)?;
+        result.extend(self.id.to_bytes()?);
+        result.extend(self.owner.to_bytes()?);
+        result.extend(self.market_id.to_bytes()?);
+        result.extend(self.position_id.to_bytes()?);
+        result.extend(self.side.to_bytes()?);
+        result.extend(self.kind.to_bytes()?);
+        result.extend(self.amount.to_bytes()?);
+        result.extend(self.price.to_bytes()?);
+        result.extend(self.trigger_price.to_bytes()?);
+        result.extend(self.status.to_bytes()?);
+        result.extend(self.created_at.to_bytes()?);
+        result.extend(self.updated_at.to_bytes()?);
+        result.extend(self.filled_amount.to_bytes()?);
+        result.extend(self.escrow.to_bytes()?);
+        result.extend(self.time_in_force.to_bytes()?);
+        result.extend(self.expires_at.to_bytes()?);
+        result.extend(self.trail_amount.to_bytes()?);
+        result.extend(self.trail_bps.to_bytes()?);
+        result.extend(self.linked_order_id.to_bytes()?);
+        result.extend(self.asset.to_bytes()?);
+        result.extend(self.slice_amount.to_bytes()?);
+        result.extend(self.interval.to_bytes()?);
+        result.extend(self.next_execution_at.to_bytes()?);
+        result.extend(self.min_price.to_bytes()?);
+        result.extend(self.max_price.to_bytes()?);
+        result.extend(self.execution_fee.to_bytes()?);
+        result.extend(self.fee_escrow.to_bytes()?);
+        Ok(result)
+    }
+
+    fn serialized_length(&self) -> usize {
+        self.id.serialized_length()
+            + self.owner.serialized_length()
+            + self.market_id.serialized_length()
+            + self.position_id.serialized_length()
+            + self.side.serialized_length()
+            + self.kind.serialized_length()
+            + self.amount.serialized_length()
+            + self.price.serialized_length()
+            + self.trigger_price.serialized_length()
+            + self.status.serialized_length()
+            + self.created_at.serialized_length()
+            + self.updated_at.serialized_length()
+            + self.filled_amount.serialized_length()
+            + self.escrow.serialized_length()
+            + self.time_in_force.serialized_length()
+            + self.expires_at.serialized_length()
+            + self.trail_amount.serialized_length()
+            + self.trail_bps.serialized_length()
+            + self.linked_order_id.serialized_length()
+            + self.asset.serialized_length()
+            + self.slice_amount.serialized_length()
+            + self.interval.serialized_length()
+            + self.next_execution_at.serialized_length()
+            + self.min_price.serialized_length()
+            + self.max_price.serialized_length()
+            + self.execution_fee.serialized_length()
+            + self.fee_escrow.serialized_length()
+    }
+}
+
+impl FromBytes for Order {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), bytesrepr::Error> {
+        let (id, rem) = u64::from_bytes(bytes)?;
+        let (owner, rem) = Key::from_bytes(rem)?;
+        let (market_id, rem) = u64::from_bytes(rem)?;
+        let (position_id, rem) = u64::from_bytes(rem)?;
+        let (side, rem) = u8::from_bytes(rem)?;
+        let (kind, rem) = u8::from_bytes(rem)?;
+        let (amount, rem) = U512::from_bytes(rem)?;
+        let (price, rem) = U512::from_bytes(rem)?;
+        let (trigger_price, rem) = U512::from_bytes(rem)?;
+        let (status, rem) = u8::from_bytes(rem)?;
+        let (created_at, rem) = u64::from_bytes(rem)?;
+        let (updated_at, rem) = u64::from_bytes(rem)?;
+        let (filled_amount, rem) = U512::from_bytes(rem)?;
+        let (escrow, rem) = U512::from_bytes(rem)?;
+        let (time_in_force, rem) = u8::from_bytes(rem)?;
+        let (expires_at, rem) = u64::from_bytes(rem)?;
+        let (trail_amount, rem) = U512::from_bytes(rem)?;
+        let (trail_bps, rem) = u32::from_bytes(rem)?;
+        let (linked_order_id, rem) = u64::from_bytes(rem)?;
+        let (asset, rem) = u8::from_bytes(rem)?;
+        let (slice_amount, rem) = U512::from_bytes(rem)?;
+        let (interval, rem) = u64::from_bytes(rem)?;
+        let (next_execution_at, rem) = u64::from_bytes(rem)?;
+        let (min_price, rem) = U512::from_bytes(rem)?;
+        let (max_price, rem) = U512::from_bytes(rem)?;
+        let (execution_fee, rem) = U512::from_bytes(rem)?;
+        let (fee_escrow, rem) = U512::from_bytes(rem)?;
+        Ok((
+            Order {
+                id,
+                owner,
+                market_id,
+                position_id,
+                side,
+                kind,
+                amount,
+                price,
+                trigger_price,
+                status,
+                created_at,
+                updated_at,
+                filled_amount,
+                escrow,
+                time_in_force,
+                expires_at,
+                trail_amount,
+                trail_bps,
+                linked_order_id,
+                asset,
+                slice_amount,
+                interval,
+                next_execution_at,
+                min_price,
+                max_price,
+                execution_fee,
+                fee_escrow,
+            },
+            rem,
+        ))
+    }
+}
+
+// CSPR value of `amount` CFLOW at a spot `price`
+fn quote_amount(amount: U512, price: U512) -> U512 {
+    amount * price / U512::from(PRICE_PRECISION)
+}
+
+// Amounts whose CSPR value rounds down to zero cannot be traded: the buyer would
+// receive CFLOW for nothing
+fn is_dust(amount: U512, price: U512) -> bool {
+    quote_amount(amount, price).is_zero()
+}
+
+fn locked_balance(dict_name: &str, owner: &Key) -> U512 {
+    let dict = get_dict(dict_name);
+    storage::dictionary_get(dict, &make_key(owner))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero())
+}
+
+fn set_locked_balance(dict_name: &str, owner: &Key, amount: U512) {
+    let dict = get_dict(dict_name);
+    storage::dictionary_put(dict, &make_key(owner), amount);
+}
+
+// Moves `amount` from the owner's vault (buys) or CFLOW balance (sells) into the
+// order's escrow
+fn lock_escrow(order: &mut Order, amount: U512) {
+    if order.side == SIDE_BUY {
+        debit_vault(&order.owner, amount);
+        let locked = locked_balance(DICT_LOCKED_CSPR, &order.owner);
+        set_locked_balance(DICT_LOCKED_CSPR, &order.owner, locked + amount);
+    } else {
+        debit_tokens(&order.owner, to_u256(amount));
+        let locked = locked_balance(DICT_LOCKED_CFLOW, &order.owner);
+        set_locked_balance(DICT_LOCKED_CFLOW, &order.owner, locked + amount);
+    }
+    order.escrow += amount;
+}
+
+// Takes `amount` out of the order's escrow; the caller decides where it goes
+fn consume_escrow(order: &mut Order, amount: U512) {
+    if amount > order.escrow {
+        runtime::revert(Error::InsufficientBalance);
+    }
+    let dict_name = if order.side == SIDE_BUY {
+        DICT_LOCKED_CSPR
+    } else {
+        DICT_LOCKED_CFLOW
+    };
+    let locked = locked_balance(dict_name, &order.owner);
+    set_locked_balance(dict_name, &order.owner, locked.saturating_sub(amount));
+    order.escrow -= amount;
+}
+
+// Returns `amount` of the order's escrow to its owner
+fn refund_escrow(order: &mut Order, amount: U512) {
+    consume_escrow(order, amount);
+    if order.side == SIDE_BUY {
+        credit_vault(&order.owner, amount);
+    } else {
+        credit_tokens(&order.owner, to_u256(amount));
+    }
+}
+
+// Returns whatever is left in the order's escrow to its owner
+fn release_escrow(order: &mut Order) {
+    let amount = order.escrow;
+    if !amount.is_zero() {
+        refund_escrow(order, amount);
+    }
+}
+
+// Reserves the current keeper fee for `executions` keeper executions from the
+// owner's vault balance
+fn lock_execution_fee(order: &mut Order, executions: u64) {
+    let fee: U512 = get_key(KEY_EXECUTION_FEE);
+    let total = fee * U512::from(executions);
+    if total.is_zero() {
+        return;
+    }
+    debit_vault(&order.owner, total);
+    let locked = locked_balance(DICT_LOCKED_CSPR, &order.owner);
+    set_locked_balance(DICT_LOCKED_CSPR, &order.owner, locked + total);
+    order.execution_fee = fee;
+    order.fee_escrow = total;
+}
+
+fn take_fee_escrow(order: &mut Order, amount: U512) {
+    let locked = locked_balance(DICT_LOCKED_CSPR, &order.owner);
+    set_locked_balance(DICT_LOCKED_CSPR, &order.owner, locked.saturating_sub(amount));
+    order.fee_escrow -= amount;
+}
+
+// Pays one execution fee to the keeper and counts the execution
+fn pay_execution_fee(order: &mut Order, keeper: &Key) {
+    let fee = core::cmp::min(order.execution_fee, order.fee_escrow);
+    take_fee_escrow(order, fee);
+    credit_vault(keeper, fee);
+
+    let mut record = read_keeper(keeper);
+    record.executions += 1;
+    record.fees_earned += fee;
+    write_keeper(keeper, &record);
+}
+
+// What an order has to lock for `amount` CFLOW at its limit price: CFLOW for sells,
+// and for buys the CSPR cost plus room for the spot taker fee
+fn order_escrow(side: u8, amount: U512, price: U512) -> U512 {
+    if side == SIDE_BUY {
+        let (_, taker_fee_bps, _) = spot_fee_schedule();
+        let quote = quote_amount(amount, price);
+        quote + bps(quote, taker_fee_bps)
+    } else {
+        amount
+    }
+}
+
+// Fills an order against the caller, who takes the opposite side. The owner's leg
+// is paid out of the order's escrow and the counterparty's from their balances.
+// The owner pays the spot maker fee.
+fn settle_spot_fill(order: &mut Order, counterparty: &Key, amount: U512, price: U512) {
+    let quote = quote_amount(amount, price);
+    if quote.is_zero() {
+        runtime::revert(Error::InvalidAmount);
+    }
+    let (maker_fee_bps, _, _) = spot_fee_schedule();
+    let fee = trading_fee(&order.owner, quote, maker_fee_bps);
+    if order.side == SIDE_BUY {
+        let fee = core::cmp::min(fee, order.escrow.saturating_sub(quote));
+        consume_escrow(order, quote + fee);
+        credit_treasury(fee);
+        credit_vault(counterparty, quote);
+        debit_tokens(counterparty, to_u256(amount));
+        credit_tokens(&order.owner, to_u256(amount));
+    } else {
+        consume_escrow(order, amount);
+        credit_tokens(counterparty, to_u256(amount));
+        debit_vault(counterparty, quote);
+        credit_vault(&order.owner, quote - fee);
+        credit_treasury(fee);
+    }
+    order.filled_amount += amount;
+}
+
+fn read_order(order_id: u64) -> Order {
+    find_order(order_id).unwrap_or_revert_with(Error::OrderNotFound)
+}
+
+fn find_order(order_id: u64) -> Option<Order> {
+    let dict = get_dict(DICT_ORDERS);
+    storage::dictionary_get(dict, &format!("{}", order_id)).unwrap_or_revert()
+}
+
+fn write_order(order: &Order) {
+    let dict = get_dict(DICT_ORDERS);
+    storage::dictionary_put(dict, &format!("{}", order.id), order.clone());
+}
+
+fn open_order_count(owner: &Key) -> u64 {
+    let dict = get_dict(DICT_OPEN_ORDER_COUNT);
+    storage::dictionary_get(dict, &make_key(owner))
+        .unwrap_or_revert()
+        .unwrap_or(0)
+}
+
+fn open_order_at(owner: &Key, slot: u64) -> u64 {
+    let dict = get_dict(DICT_OPEN_ORDERS);
+    storage::dictionary_get(dict, &format!("{}_{}", make_key(owner), slot))
+        .unwrap_or_revert()
+        .unwrap_or_revert_with(Error::OrderNotFound)
+}
+
+fn owner_order_count(owner: &Key) -> u64 {
+    let dict = get_dict(DICT_OWNER_ORDER_COUNT);
+    storage::dictionary_get(dict, &make_key(owner))
+        .unwrap_or_revert()
+        .unwrap_or(0)
+}
+
+// Reads `limit` order ids (at most MAX_PAGE_SIZE) from `offset` of either the
+// owner's open set or their full order history
+fn owner_order_ids(owner: &Key, open: bool, offset: u64, limit: u64) -> Vec<u64> {
+    let count = if open {
+        open_order_count(owner)
+    } else {
+        owner_order_count(owner)
+    };
+    let page_end = offset.saturating_add(core::cmp::min(limit, MAX_PAGE_SIZE));
+    let end = core::cmp::min(count, page_end);
+    let dict = get_dict(if open { DICT_OPEN_ORDERS } else { DICT_OWNER_ORDERS });
+    (offset..end)
+        .map(|index| {
+            storage::dictionary_get(dict, &format!("{}_{}", make_key(owner), index))
+                .unwrap_or_revert()
+                .unwrap_or_revert()
+        })
+        .collect()
+}
+
+// Appends the order to its owner's order history and adds it to their open set.
+// The open set is kept dense by swap-removal like the market position index.
+fn index_order(order: &Order) {
+    let total = owner_order_count(&order.owner);
+    let history = get_dict(DICT_OWNER_ORDERS);
+    storage::dictionary_put(history, &format!("{}_{}", make_key(&order.owner), total), order.id);
+    let totals = get_dict(DICT_OWNER_ORDER_COUNT);
+    storage::dictionary_put(totals, &make_key(&order.owner), total + 1);
+
+    let count = open_order_count(&order.owner);
+
+    let dict = get_dict(DICT_OPEN_ORDERS);
+    storage::dictionary_put(dict, &format!("{}_{}", make_key(&order.owner), count), order.id);
+
+    let slots = get_dict(DICT_ORDER_SLOTS);
+    storage::dictionary_put(slots, &format!("{}", order.id), count);
+
+    let counts = get_dict(DICT_OPEN_ORDER_COUNT);
+    storage::dictionary_put(counts, &make_key(&order.owner), count + 1);
+}
+
+fn unindex_order(order: &Order) {
+    let count = open_order_count(&order.owner);
+    let slots = get_dict(DICT_ORDER_SLOTS);
+    let slot: u64 = storage::dictionary_get(slots, &format!("{}", order.id))
+        .unwrap_or_revert()
+        .unwrap_or_revert_with(Error::OrderNotFound);
+    // Slots are not cleared on removal; an order already out of the open set is left
+    if slot >= count || open_order_at(&order.owner, slot) != order.id {
+        return;
+    }
+
+    let last = count - 1;
+    if slot != last {
+        let moved = open_order_at(&order.owner, last);
+        let dict = get_dict(DICT_OPEN_ORDERS);
+        storage::dictionary_put(dict, &format!("{}_{}", make_key(&order.owner), slot), moved);
+        storage::dictionary_put(slots, &format!("{}", moved), slot);
+    }
+
+    let counts = get_dict(DICT_OPEN_ORDER_COUNT);
+    storage::dictionary_put(counts, &make_key(&order.owner), last);
+}
+
+// Reads an order the caller is allowed to cancel: it must exist, belong to
+// `owner` and still be open
+fn read_cancellable_order(order_id: u64, owner: &Key) -> Order {
+    check_cancellable(order_id, owner).unwrap_or_else(|error| runtime::revert(error))
+}
+
+fn check_cancellable(order_id: u64, owner: &Key) -> Result<Order, Error> {
+    let order = find_order(order_id).ok_or(Error::OrderNotFound)?;
+    if order.owner != *owner {
+        return Err(Error::Unauthorized);
+    }
+    if order.status != ORDER_STATUS_OPEN {
+        return Err(Error::OrderNotOpen);
+    }
+    Ok(order)
+}
+
+// Refunds the order's remaining escrow and closes it with a final `status`
+fn finish_order(order: &mut Order, status: u8) {
+    release_escrow(order);
+    let unused_fee = order.fee_escrow;
+    if !unused_fee.is_zero() {
+        take_fee_escrow(order, unused_fee);
+        credit_vault(&order.owner, unused_fee);
+    }
+    order.status = status;
+    order.updated_at = runtime::get_blocktime().into();
+    write_order(order);
+    unindex_order(order);
+    if order.position_id != 0 {
+        unlink_position_order(order);
+    }
+    if order.linked_order_id != 0 {
+        let mut linked = read_order(order.linked_order_id);
+        if linked.status == ORDER_STATUS_OPEN {
+            finish_order(&mut linked, ORDER_STATUS_CANCELLED);
+        }
+    }
+}
+
+// (stop-loss, take-profit) order ids attached to a position, zero when unset
+fn position_orders(owner: &Key, position_id: u64) -> (u64, u64) {
+    let dict = get_dict(DICT_POSITION_ORDERS);
+    storage::dictionary_get(dict, &position_key(owner, position_id))
+        .unwrap_or_revert()
+        .unwrap_or((0, 0))
+}
+
+fn set_position_orders(owner: &Key, position_id: u64, orders: (u64, u64)) {
+    let dict = get_dict(DICT_POSITION_ORDERS);
+    storage::dictionary_put(dict, &position_key(owner, position_id), orders);
+}
+
+fn unlink_position_order(order: &Order) {
+    let (stop_loss, take_profit) = position_orders(&order.owner, order.position_id);
+    if stop_loss == order.id {
+        set_position_orders(&order.owner, order.position_id, (0, take_profit));
+    } else if take_profit == order.id {
+        set_position_orders(&order.owner, order.position_id, (stop_loss, 0));
+    }
+}
+
+// Cancels the stop-loss and take-profit orders of a position that has been closed
+fn cancel_position_orders(position: &Position) {
+    let (stop_loss, take_profit) = position_orders(&position.owner, position.id);
+    for order_id in [stop_loss, take_profit] {
+        if order_id == 0 {
+            continue;
+        }
+        // Cancelling one half of an OCO pair already cancels the other
+        let mut order = read_order(order_id);
+        if order.status == ORDER_STATUS_OPEN {
+            finish_order(&mut order, ORDER_STATUS_CANCELLED);
+        }
+    }
+}
+
+// Whether the oracle `price` has reached a stop order's trigger. Stops on longs and
+// take-profits on shorts trigger on a fall, the others on a rise.
+fn is_triggered(order: &Order, price: U512) -> bool {
+    let on_fall = (order.kind != ORDER_KIND_TAKE_PROFIT) == (order.side == SIDE_LONG);
+    if on_fall {
+        price <= order.trigger_price
+    } else {
+        price >= order.trigger_price
+    }
+}
+
+// Trigger of a trailing stop, `trail_amount` or `trail_bps` behind its best price
+fn trailing_trigger(order: &Order) -> U512 {
+    let delta = if order.trail_bps == 0 {
+        order.trail_amount
+    } else {
+        bps(order.price, order.trail_bps)
+    };
+    if order.side == SIDE_LONG {
+        order.price.saturating_sub(delta)
+    } else {
+        order.price + delta
+    }
+}
+
+// Moves a trailing stop's best price and trigger to `price` if it improved on the
+// best price. Returns whether the stop moved.
+fn trail_stop(order: &mut Order, price: U512) -> bool {
+    let improved = if order.side == SIDE_LONG {
+        price > order.price
+    } else {
+        price < order.price
+    };
+    if improved {
+        order.price = price;
+        order.trigger_price = trailing_trigger(order);
+    }
+    improved
+}
+
+// Builds an unsaved order closing `size` of the position, zero for all of it
+fn new_position_order(position: &Position, kind: u8, size: U512, expires_at: u64) -> Order {
+    if size > position.size {
+        runtime::revert(Error::InvalidAmount);
+    }
+    let now: u64 = runtime::get_blocktime().into();
+    if expires_at != 0 && expires_at <= now {
+        runtime::revert(Error::OrderExpired);
+    }
+
+    let zero = U512::zero();
+    let mut order = new_order(position.owner, position.side, kind, size, zero, zero);
+    order.market_id = position.market_id;
+    order.position_id = position.id;
+    if expires_at != 0 {
+        order.time_in_force = TIF_GTT;
+        order.expires_at = expires_at;
+    }
+    lock_execution_fee(&mut order, 1);
+    order
+}
+
+// Stores a position order, replacing the position's previous order in the same
+// slot: take-profits use one slot, stop-losses and trailing stops the other.
+// Triggers that would fire at the current price are rejected.
+fn store_position_order(position: &Position, order: &Order) {
+    if order.trigger_price.is_zero() || is_triggered(order, market_price(position.market_id)) {
+        runtime::revert(Error::InvalidTriggerPrice);
+    }
+
+    let is_take_profit = order.kind == ORDER_KIND_TAKE_PROFIT;
+    let (stop_loss, take_profit) = position_orders(&position.owner, position.id);
+    let replaced = if is_take_profit { take_profit } else { stop_loss };
+    if replaced != 0 {
+        let mut previous = read_order(replaced);
+        finish_order(&mut previous, ORDER_STATUS_CANCELLED);
+    }
+
+    write_order(order);
+    index_order(order);
+    let (stop_loss, take_profit) = position_orders(&position.owner, position.id);
+    let orders = if is_take_profit {
+        (stop_loss, order.id)
+    } else {
+        (order.id, take_profit)
+    };
+    set_position_orders(&position.owner, position.id, orders);
+}
+
+fn attach_position_order(
+    position: &Position,
+    kind: u8,
+    size: U512,
+    trigger_price: U512,
+    expires_at: u64,
+) -> Order {
+    let mut order = new_position_order(position, kind, size, expires_at);
+    order.trigger_price = trigger_price;
+    store_position_order(position, &order);
+    order
+}
+
+// Shared body of `create_stop_loss` and `create_take_profit`
+fn create_position_order(kind: u8) {
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+    let size: U512 = runtime::get_named_arg(ARG_SIZE);
+    let trigger_price: U512 = runtime::get_named_arg(ARG_TRIGGER_PRICE);
+    let expires_at: u64 = runtime::try_get_named_arg(ARG_EXPIRES_AT).unwrap_or(0);
+    let caller = Key::from(runtime::get_caller());
+
+    let position = read_position(&caller, position_id);
+    attach_position_order(&position, kind, size, trigger_price, expires_at);
+}
+
+fn cancel_open_order(order: &mut Order) {
+    // Open limit orders always rest on the book
+    if order.kind == ORDER_KIND_LIMIT {
+        remove_from_book(order);
+    }
+    finish_order(order, ORDER_STATUS_CANCELLED);
+}
+
+fn new_order(
+    owner: Key,
+    side: u8,
+    kind: u8,
+    amount: U512,
+    price: U512,
+    trigger_price: U512,
+) -> Order {
+    let counter: u64 = get_key(KEY_ORDER_COUNTER);
+    let order_id = counter + 1;
+    set_key(KEY_ORDER_COUNTER, order_id);
+
+    let now: u64 = runtime::get_blocktime().into();
+    Order {
+        id: order_id,
+        owner,
+        market_id: SPOT_MARKET_ID,
+        position_id: 0,
+        side,
+        kind,
+        amount,
+        price,
+        trigger_price,
+        status: ORDER_STATUS_OPEN,
+        created_at: now,
+        updated_at: now,
+        filled_amount: U512::zero(),
+        escrow: U512::zero(),
+        time_in_force: TIF_GTC,
+        expires_at: 0,
+        trail_amount: U512::zero(),
+        trail_bps: 0,
+        linked_order_id: 0,
+        asset: ASSET_CFLOW,
+        slice_amount: U512::zero(),
+        interval: 0,
+        next_execution_at: 0,
+        min_price: U512::zero(),
+        max_price: U512::zero(),
+        execution_fee: U512::zero(),
+        fee_escrow: U512::zero(),
+    }
+}
+
+// Resting limit orders form a price-time priority book per side. Price levels are a
+// doubly linked list sorted from the best price (`best_bid` / `best_ask`) outwards,
+// and each level is a FIFO queue of order ids. A zero price or id ends a list.
+#[derive(Clone)]
+struct PriceLevel {
+    price: U512,
+    prev: U512,
+    next: U512,
+    head: u64,
+    tail: u64,
+    amount: U512,
+}
+
+impl CLTyped for PriceLevel {
+    fn cl_type() -> CLType {
+        CLType::Any
+    }
+}
+
+impl ToBytes for PriceLevel {
+    fn to_bytes(&self) -> Result<Vec<u8>, bytesrepr::Error> {
+        let mut result = bytesrepr::allocate_buffer(self)?;
+        result.extend(self.price.to_bytes()?);
+        result.extend(self.prev.to_bytes()?);
+        result.extend(self.next.to_bytes()?);
+        result.extend(self.head.to_bytes()?);
+        result.extend(self.tail.to_bytes()?);
+        result.extend(self.amount.to_bytes()?);
+        Ok(result)
+    }
+
+    fn serialized_length(&self) -> usize {
+        self.price.serialized_length()
+            + self.prev.serialized_length()
+            + self.next.serialized_length()
+            + self.head.serialized_length()
+            + self.tail.serialized_length()
+            + self.amount.serialized_length()
+    }
+}
+
+impl FromBytes for PriceLevel {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), bytesrepr::Error> {
+        let (price, rem) = U512::from_bytes(bytes)?;
+        let (prev, rem) = U512::from_bytes(rem)?;
+        let (next, rem) = U512::from_bytes(rem)?;
+        let (head, rem) = u64::from_bytes(rem)?;
+        let (tail, rem) = u64::from_bytes(rem)?;
+        let (amount, rem) = U512::from_bytes(rem)?;
+        Ok((
+            PriceLevel {
+                price,
+                prev,
+                next,
+                head,
+                tail,
+                amount,
+            },
+            rem,
+        ))
+    }
+}
+
+fn best_price_key(side: u8) -> &'static str {
+    if side == SIDE_BUY {
+        KEY_BEST_BID
+    } else {
+        KEY_BEST_ASK
+    }
+}
+
+fn best_price(side: u8) -> U512 {
+    get_key(best_price_key(side))
+}
+
+// Bids improve upwards, asks downwards
+fn is_better_price(side: u8, price: U512, than: U512) -> bool {
+    if side == SIDE_BUY {
+        price > than
+    } else {
+        price < than
+    }
+}
+
+fn level_key(side: u8, price: U512) -> String {
+    format!("{}_{}", side, price)
+}
+
+fn read_level(side: u8, price: U512) -> Option<PriceLevel> {
+    let dict = get_dict(DICT_BOOK_LEVELS);
+    storage::dictionary_get(dict, &level_key(side, price)).unwrap_or_revert()
+}
+
+fn write_level(side: u8, level: &PriceLevel) {
+    let dict = get_dict(DICT_BOOK_LEVELS);
+    storage::dictionary_put(dict, &level_key(side, level.price), level.clone());
+}
+
+fn set_level_prev(side: u8, price: U512, prev: U512) {
+    let mut level = read_level(side, price).unwrap_or_revert_with(Error::OrderNotFound);
+    level.prev = prev;
+    write_level(side, &level);
+}
+
+fn set_level_next(side: u8, price: U512, next: U512) {
+    let mut level = read_level(side, price).unwrap_or_revert_with(Error::OrderNotFound);
+    level.next = next;
+    write_level(side, &level);
+}
+
+// (previous, next) order ids in the order's price level queue
+fn order_links(order_id: u64) -> (u64, u64) {
+    let dict = get_dict(DICT_BOOK_LINKS);
+    storage::dictionary_get(dict, &format!("{}", order_id))
+        .unwrap_or_revert()
+        .unwrap_or((0, 0))
+}
+
+fn set_order_links(order_id: u64, links: (u64, u64)) {
+    let dict = get_dict(DICT_BOOK_LINKS);
+    storage::dictionary_put(dict, &format!("{}", order_id), links);
+}
+
+// Returns the level at `price`, creating and linking it into the sorted level list
+// when missing or empty. The walk is bounded by MAX_BOOK_ITERATIONS and starts from
+// `price_hint` when that is a live level better than `price`, so callers can insert
+// deep in the book; otherwise it starts from the best price.
+fn find_or_insert_level(side: u8, price: U512, price_hint: U512) -> PriceLevel {
+    if let Some(level) = read_level(side, price) {
+        if level.head != 0 {
+            return level;
+        }
+    }
+
+    let (prev, next) = level_neighbours(side, price, price_hint, U512::zero())
+        .unwrap_or_else(|error| runtime::revert(error));
+    if prev.is_zero() {
+        set_key(best_price_key(side), price);
+    } else {
+        set_level_next(side, prev, price);
+    }
+    if !next.is_zero() {
+        set_level_prev(side, next, price);
+    }
+
+    PriceLevel {
+        price,
+        prev,
+        next,
+        head: 0,
+        tail: 0,
+        amount: U512::zero(),
+    }
+}
+
+// Walks the level list to the (previous, next) levels a new level at `price` would
+// be linked between. The level at `vacated` (zero for none) is treated as already
+// unlinked, for orders that leave their own level before re-entering the book.
+fn level_neighbours(
+    side: u8,
+    price: U512,
+    price_hint: U512,
+    vacated: U512,
+) -> Result<(U512, U512), Error> {
+    let hint = if price_hint.is_zero()
+        || price_hint == vacated
+        || !is_better_price(side, price_hint, price)
+    {
+        None
+    } else {
+        read_level(side, price_hint).filter(|level| level.head != 0)
+    };
+    let (mut prev, mut next) = match hint {
+        Some(level) => (level.price, level.next),
+        None => (U512::zero(), best_price(side)),
+    };
+    let mut steps = 0;
+    while !next.is_zero() && is_better_price(side, next, price) {
+        if next != vacated {
+            steps += 1;
+            if steps > MAX_BOOK_ITERATIONS {
+                return Err(Error::OrderBookTooDeep);
+            }
+            prev = next;
+        }
+        next = read_level(side, next).unwrap_or_revert_with(Error::OrderNotFound).next;
+    }
+    Ok((prev, next))
+}
+
+// Checks what `find_or_insert_level` would otherwise revert on
+fn check_level_depth(side: u8, price: U512, price_hint: U512, vacated: U512) -> Result<(), Error> {
+    if price != vacated && read_level(side, price).is_some_and(|level| level.head != 0) {
+        return Ok(());
+    }
+    level_neighbours(side, price, price_hint, vacated).map(|_| ())
+}
+
+// Appends the unfilled part of an order to the back of its price level
+fn add_to_book(order: &Order, price_hint: U512) {
+    let mut level = find_or_insert_level(order.side, order.price, price_hint);
+    if level.tail == 0 {
+        level.head = order.id;
+    } else {
+        let (prev, _) = order_links(level.tail);
+        set_order_links(level.tail, (prev, order.id));
+    }
+    set_order_links(order.id, (level.tail, 0));
+    level.tail = order.id;
+    level.amount += order.amount - order.filled_amount;
+    write_level(order.side, &level);
+}
+
+// Unlinks an order from its price level, dropping the level once it is empty
+fn remove_from_book(order: &Order) {
+    let mut level = read_level(order.side, order.price).unwrap_or_revert_with(Error::OrderNotFound);
+    let (prev, next) = order_links(order.id);
+    if prev == 0 {
+        level.head = next;
+    } else {
+        let (prev_prev, _) = order_links(prev);
+        set_order_links(prev, (prev_prev, next));
+    }
+    if next == 0 {
+        level.tail = prev;
+    } else {
+        let (_, next_next) = order_links(next);
+        set_order_links(next, (prev, next_next));
+    }
+    set_order_links(order.id, (0, 0));
+    level.amount = level.amount.saturating_sub(order.amount - order.filled_amount);
+
+    if level.head != 0 {
+        write_level(order.side, &level);
+        return;
+    }
+
+    if level.prev.is_zero() {
+        set_key(best_price_key(order.side), level.next);
+    } else {
+        set_level_next(order.side, level.prev, level.next);
+    }
+    if !level.next.is_zero() {
+        set_level_prev(order.side, level.next, level.prev);
+    }
+    // Dictionaries cannot delete, so an emptied level is left behind unlinked
+    level.prev = U512::zero();
+    level.next = U512::zero();
+    write_level(order.side, &level);
+}
+
+// Settles `amount` between a resting maker and an incoming taker at the maker's
+// price, paying both legs out of the two orders' escrow. The buyer's fee comes out
+// of its escrow and the seller's out of its CSPR proceeds; the maker rebate is
+// funded by the fees and the rest goes to the treasury.
+fn settle_match(taker: &mut Order, maker: &mut Order, amount: U512, price: U512) {
+    let quote = quote_amount(amount, price);
+    if quote.is_zero() {
+        runtime::revert(Error::InvalidAmount);
+    }
+    let (maker_fee_bps, taker_fee_bps, rebate_bps) = spot_fee_schedule();
+    let taker_fee = trading_fee(&taker.owner, quote, taker_fee_bps);
+    let maker_fee = trading_fee(&maker.owner, quote, maker_fee_bps);
+    let maker_owner = maker.owner;
+    let (buyer, seller, buyer_fee, seller_fee) = if taker.side == SIDE_BUY {
+        (taker, maker, taker_fee, maker_fee)
+    } else {
+        (maker, taker, maker_fee, taker_fee)
+    };
+
+    // Escrow was sized with the fee schedule at placement
+    let buyer_fee = core::cmp::min(buyer_fee, buyer.escrow.saturating_sub(quote));
+    let rebate = core::cmp::min(bps(quote, rebate_bps), buyer_fee + seller_fee);
+    consume_escrow(buyer, quote + buyer_fee);
+    consume_escrow(seller, amount);
+    credit_vault(&seller.owner, quote - seller_fee);
+    credit_tokens(&buyer.owner, to_u256(amount));
+    credit_vault(&maker_owner, rebate);
+    credit_treasury(buyer_fee + seller_fee - rebate);
+    buyer.filled_amount += amount;
+    seller.filled_amount += amount;
+    buyer.updated_at = runtime::get_blocktime().into();
+    seller.updated_at = buyer.updated_at;
+
+    emit_event(format!(
+        "order_filled buy_order_id={} sell_order_id={} amount={} price={}",
+        buyer.id, seller.id, amount, price
+    ));
+}
+
+// Matches an incoming limit order against the opposite side of the book at the
+// resting prices, best level first and oldest order first within a level. At most
+// MAX_BOOK_ITERATIONS makers are filled per call; expired makers met on the way are
+// dropped from the book and count towards that budget, and makers left with a dust
+// remainder are cancelled. Returns whether liquidity that crosses the order's price
+// is still left on the book, which includes the order's own remainder being dust.
+fn match_order(order: &mut Order) -> bool {
+    let opposite = if order.side == SIDE_BUY { SIDE_SELL } else { SIDE_BUY };
+    let mut fills = 0;
+    loop {
+        let best = best_price(opposite);
+        let crosses = !best.is_zero() && !is_better_price(opposite, order.price, best);
+        if !crosses || order.filled_amount == order.amount {
+            return false;
+        }
+        if fills == MAX_BOOK_ITERATIONS {
+            return true;
+        }
+        fills += 1;
+
+        let mut level = read_level(opposite, best).unwrap_or_revert_with(Error::OrderNotFound);
+        let mut maker = read_order(level.head);
+        if is_expired(&maker) {
+            remove_from_book(&maker);
+            finish_order(&mut maker, ORDER_STATUS_EXPIRED);
+            continue;
+        }
+        let amount = core::cmp::min(
+            order.amount - order.filled_amount,
+            maker.amount - maker.filled_amount,
+        );
+        if is_dust(amount, best) {
+            return true;
+        }
+        settle_match(order, &mut maker, amount, best);
+
+        level.amount -= amount;
+        write_level(opposite, &level);
+        if maker.filled_amount == maker.amount {
+            remove_from_book(&maker);
+            finish_order(&mut maker, ORDER_STATUS_EXECUTED);
+        } else if is_dust(maker.amount - maker.filled_amount, best) {
+            remove_from_book(&maker);
+            finish_order(&mut maker, ORDER_STATUS_CANCELLED);
+        } else {
+            write_order(&maker);
+        }
+    }
+}
+
+// Dry run of `match_order` for an incoming `amount` on `side` at `price`, walking the
+// same makers under the same fill budget without writing anything. Returns how much
+// would fill and whether crossing liquidity would be left on the book.
+fn preview_match(side: u8, price: U512, amount: U512) -> (U512, bool) {
+    let opposite = if side == SIDE_BUY { SIDE_SELL } else { SIDE_BUY };
+    let mut remaining = amount;
+    let mut best = best_price(opposite);
+    let mut next_best = U512::zero();
+    let mut maker_id = 0;
+    let mut fills = 0;
+    loop {
+        let crosses = !best.is_zero() && !is_better_price(opposite, price, best);
+        if !crosses || remaining.is_zero() {
+            return (amount - remaining, false);
+        }
+        if fills == MAX_BOOK_ITERATIONS {
+            return (amount - remaining, true);
+        }
+        fills += 1;
+
+        if maker_id == 0 {
+            let level = read_level(opposite, best).unwrap_or_revert_with(Error::OrderNotFound);
+            maker_id = level.head;
+            next_best = level.next;
+        }
+        let maker = read_order(maker_id);
+        let maker_price = best;
+        // Filled, expired and dust makers all leave the book, exposing the next one
+        maker_id = order_links(maker.id).1;
+        if maker_id == 0 {
+            best = next_best;
+        }
+        if is_expired(&maker) {
+            continue;
+        }
+        let fill = core::cmp::min(remaining, maker.amount - maker.filled_amount);
+        if is_dust(fill, maker_price) {
+            return (amount - remaining, true);
+        }
+        remaining -= fill;
+    }
+}
+
+fn is_expired(order: &Order) -> bool {
+    let now: u64 = runtime::get_blocktime().into();
+    order.expires_at != 0 && now >= order.expires_at
+}
+
+// Optional (time_in_force, expires_at) args of the order placing entry points,
+// defaulting to good-till-cancelled
+fn time_in_force_args() -> (u8, u64) {
+    let time_in_force = runtime::try_get_named_arg(ARG_TIME_IN_FORCE).unwrap_or(TIF_GTC);
+    let expires_at = runtime::try_get_named_arg(ARG_EXPIRES_AT).unwrap_or(0);
+    (time_in_force, expires_at)
+}
+
+// Places a limit order: locks its escrow, fills what crosses the book and rests the
+// remainder as its time in force allows. A remainder that still crosses once the
+// fill budget is spent is cancelled rather than left on a crossed book. Callers
+// validate the order with `check_limit_order` first.
+fn place_limit_order(
+    owner: Key,
+    side: u8,
+    amount: U512,
+    price: U512,
+    time_in_force: u8,
+    expires_at: u64,
+    price_hint: U512,
+) -> Order {
+    let mut order = new_order(owner, side, ORDER_KIND_LIMIT, amount, price, U512::zero());
+    order.time_in_force = time_in_force;
+    order.expires_at = expires_at;
+    lock_escrow(&mut order, order_escrow(side, amount, price));
+    lock_execution_fee(&mut order, 1);
+    write_order(&order);
+    index_order(&order);
+    enter_book(&mut order, price_hint);
+    order
+}
+
+fn check_limit_order(
+    side: u8,
+    amount: U512,
+    price: U512,
+    time_in_force: u8,
+    expires_at: u64,
+) -> Result<(), Error> {
+    if amount.is_zero() || is_dust(amount, price) {
+        return Err(Error::InvalidAmount);
+    }
+    if side != SIDE_BUY && side != SIDE_SELL {
+        return Err(Error::InvalidSide);
+    }
+    if time_in_force > TIF_POST_ONLY {
+        return Err(Error::InvalidTimeInForce);
+    }
+    let now: u64 = runtime::get_blocktime().into();
+    if (time_in_force == TIF_GTT) != (expires_at != 0) {
+        return Err(Error::InvalidTimeInForce);
+    }
+    if time_in_force == TIF_GTT && expires_at <= now {
+        return Err(Error::OrderExpired);
+    }
+    Ok(())
+}
+
+// Matches an escrowed limit order against the book, then rests, fills or cancels
+// what is left according to its time in force. A remainder too small to be worth
+// any CSPR at its price is cancelled instead of resting.
+fn enter_book(order: &mut Order, price_hint: U512) {
+    let crossed = if order.time_in_force == TIF_POST_ONLY {
+        check_post_only(order.side, order.price).unwrap_or_else(|error| runtime::revert(error));
+        false
+    } else {
+        match_order(order)
+    };
+
+    if order.filled_amount == order.amount {
+        finish_order(order, ORDER_STATUS_EXECUTED);
+    } else if order.time_in_force == TIF_FOK {
+        // Reverting unwinds every fill made above
+        runtime::revert(Error::OrderNotFilled);
+    } else if crossed
+        || order.time_in_force == TIF_IOC
+        || is_dust(order.amount - order.filled_amount, order.price)
+    {
+        finish_order(order, ORDER_STATUS_CANCELLED);
+    } else {
+        // Buys filled below their limit keep escrow for the remainder only
+        let remaining = order_escrow(order.side, order.amount - order.filled_amount, order.price);
+        let excess = order.escrow - remaining;
+        refund_escrow(order, excess);
+        add_to_book(order, price_hint);
+        write_order(order);
+    }
+}
+
+// One step of `batch_orders`. Creates use `side`, `amount`, `price`, `time_in_force`
+// and `expires_at`; cancels use `order_id`; modifies use `order_id` with the new
+// `price` and total `amount`. Creates and modifies take an optional `price_hint`
+// (`0` for none) as `place_order` does.
+#[derive(Clone)]
+struct OrderInstruction {
+    action: u8,
+    order_id: u64,
+    side: u8,
+    amount: U512,
+    price: U512,
+    time_in_force: u8,
+    expires_at: u64,
+    price_hint: U512,
+}
+
+impl CLTyped for OrderInstruction {
+    fn cl_type() -> CLType {
+        CLType::Any
+    }
+}
+
+impl ToBytes for OrderInstruction {
+    fn to_bytes(&self) -> Result<Vec<u8>, bytesrepr::Error> {
+        let mut result = bytesrepr::allocate_buffer(self)?;
+        result.extend(self.action.to_bytes()?);
+        result.extend(self.order_id.to_bytes()?);
+        result.extend(self.side.to_bytes()?);
+        result.extend(self.amount.to_bytes()?);
+        result.extend(self.price.to_bytes()?);
+        result.extend(self.time_in_force.to_bytes()?);
+        result.extend(self.expires_at.to_bytes()?);
+        result.extend(self.price_hint.to_bytes()?);
+        Ok(result)
+    }
+
+    fn serialized_length(&self) -> usize {
+        self.action.serialized_length()
+            + self.order_id.serialized_length()
+            + self.side.serialized_length()
+            + self.amount.serialized_length()
+            + self.price.serialized_length()
+            + self.time_in_force.serialized_length()
+            + self.expires_at.serialized_length()
+            + self.price_hint.serialized_length()
+    }
+}
+
+impl FromBytes for OrderInstruction {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), bytesrepr::Error> {
+        let (action, rem) = u8::from_bytes(bytes)?;
+        let (order_id, rem) = u64::from_bytes(rem)?;
+        let (side, rem) = u8::from_bytes(rem)?;
+        let (amount, rem) = U512::from_bytes(rem)?;
+        let (price, rem) = U512::from_bytes(rem)?;
+        let (time_in_force, rem) = u8::from_bytes(rem)?;
+        let (expires_at, rem) = u64::from_bytes(rem)?;
+        let (price_hint, rem) = U512::from_bytes(rem)?;
+        Ok((
+            OrderInstruction {
+                action,
+                order_id,
+                side,
+                amount,
+                price,
+                time_in_force,
+                expires_at,
+                price_hint,
+            },
+            rem,
+        ))
+    }
+}
+
+// Checks that `owner` can lock `escrow` for an order on `side` plus `fee` from the vault
+fn check_order_funds(owner: &Key, side: u8, escrow: U512, fee: U512) -> Result<(), Error> {
+    let (cspr, cflow) = if side == SIDE_BUY {
+        (escrow + fee, U512::zero())
+    } else {
+        (fee, escrow)
+    };
+    if vault_balance(owner) < cspr || token_balance(owner) < to_u256(cflow) {
+        return Err(Error::InsufficientBalance);
+    }
+    Ok(())
+}
+
+// Checks what `enter_book` would otherwise revert on for a post-only order
+fn check_post_only(side: u8, price: U512) -> Result<(), Error> {
+    let opposite = if side == SIDE_BUY { SIDE_SELL } else { SIDE_BUY };
+    let best = best_price(opposite);
+    if !best.is_zero() && !is_better_price(opposite, price, best) {
+        return Err(Error::PostOnlyWouldCross);
+    }
+    Ok(())
+}
+
+// Checks a modification of an open limit order to `new_price` and a total size of
+// `new_amount`, which cannot drop to what is already filled
+fn check_modify(order: &Order, new_price: U512, new_amount: U512) -> Result<(), Error> {
+    if order.kind != ORDER_KIND_LIMIT {
+        return Err(Error::InvalidOrderKind);
+    }
+    if is_expired(order) {
+        return Err(Error::OrderExpired);
+    }
+    if new_amount <= order.filled_amount || is_dust(new_amount - order.filled_amount, new_price)
+    {
+        return Err(Error::InvalidAmount);
+    }
+    let required = order_escrow(order.side, new_amount - order.filled_amount, new_price);
+    let extra = required.saturating_sub(order.escrow);
+    check_order_funds(&order.owner, order.side, extra, U512::zero())?;
+    if order.time_in_force == TIF_POST_ONLY && new_price != order.price {
+        check_post_only(order.side, new_price)?;
+    }
+    Ok(())
+}
+
+// Amends an open limit order under its id. Moving the price or growing the order
+// sends it to the back of the queue at its new price, matching first if it now
+// crosses; shrinking it at the same price keeps its place. Callers validate the
+// change with `check_modify` first.
+fn modify_limit_order(order: &mut Order, new_price: U512, new_amount: U512, price_hint: U512) {
+    let requeue = is_requeue(order, new_price, new_amount);
+    if requeue {
+        remove_from_book(order);
+    } else {
+        let mut level =
+            read_level(order.side, order.price).unwrap_or_revert_with(Error::OrderNotFound);
+        level.amount -= order.amount - new_amount;
+        write_level(order.side, &level);
+    }
+    order.price = new_price;
+    order.amount = new_amount;
+    order.updated_at = runtime::get_blocktime().into();
+
+    let required = order_escrow(order.side, new_amount - order.filled_amount, new_price);
+    if required > order.escrow {
+        let extra = required - order.escrow;
+        lock_escrow(order, extra);
+    } else {
+        let excess = order.escrow - required;
+        refund_escrow(order, excess);
+    }
+
+    emit_event(format!(
+        "order_modified order_id={} price={} amount={}",
+        order.id, new_price, new_amount
+    ));
+    if requeue {
+        enter_book(order, price_hint);
+    } else {
+        write_order(order);
+    }
+}
+
+fn is_requeue(order: &Order, new_price: U512, new_amount: U512) -> bool {
+    new_price != order.price || new_amount > order.amount
+}
+
+// Checks what `enter_book` would otherwise revert on for `remaining` entering the
+// book on `side`: post-only crossing, an FOK order the book cannot fill and a
+// remainder that would rest deeper than the level walk reaches
+fn check_enter_book(
+    side: u8,
+    price: U512,
+    remaining: U512,
+    time_in_force: u8,
+    price_hint: U512,
+    vacated: U512,
+) -> Result<(), Error> {
+    let (filled, crossed) = if time_in_force == TIF_POST_ONLY {
+        check_post_only(side, price)?;
+        (U512::zero(), false)
+    } else {
+        preview_match(side, price, remaining)
+    };
+
+    let left = remaining - filled;
+    if left.is_zero() {
+        return Ok(());
+    }
+    if time_in_force == TIF_FOK {
+        return Err(Error::OrderNotFilled);
+    }
+    if crossed || time_in_force == TIF_IOC || is_dust(left, price) {
+        return Ok(());
+    }
+    check_level_depth(side, price, price_hint, vacated)
+}
+
+// Validates and applies one batch instruction, returning the id of the order it
+// created or touched. Everything the single-order entry points would revert on is
+// checked up front, so a skipped instruction leaves no partial effects.
+fn apply_instruction(instruction: &OrderInstruction, owner: &Key) -> Result<u64, Error> {
+    match instruction.action {
+        ORDER_ACTION_CREATE => {
+            check_limit_order(
+                instruction.side,
+                instruction.amount,
+                instruction.price,
+                instruction.time_in_force,
+                instruction.expires_at,
+            )?;
+            let escrow = order_escrow(instruction.side, instruction.amount, instruction.price);
+            let fee: U512 = get_key(KEY_EXECUTION_FEE);
+            check_order_funds(owner, instruction.side, escrow, fee)?;
+            check_enter_book(
+                instruction.side,
+                instruction.price,
+                instruction.amount,
+                instruction.time_in_force,
+                instruction.price_hint,
+                U512::zero(),
+            )?;
+            let order = place_limit_order(
+                *owner,
+                instruction.side,
+                instruction.amount,
+                instruction.price,
+                instruction.time_in_force,
+                instruction.expires_at,
+                instruction.price_hint,
+            );
+            Ok(order.id)
+        }
+        ORDER_ACTION_CANCEL => {
+            let mut order = check_cancellable(instruction.order_id, owner)?;
+            cancel_open_order(&mut order);
+            Ok(order.id)
+        }
+        ORDER_ACTION_MODIFY => {
+            let mut order = check_cancellable(instruction.order_id, owner)?;
+            let (price, amount) = (instruction.price, instruction.amount);
+            check_modify(&order, price, amount)?;
+            if is_requeue(&order, price, amount) {
+                // The order leaves its level first, which unlinks the level if it is alone
+                let level =
+                    read_level(order.side, order.price).unwrap_or_revert_with(Error::OrderNotFound);
+                let alone = level.head == order.id && level.tail == order.id;
+                let vacated = if alone { order.price } else { U512::zero() };
+                let remaining = amount - order.filled_amount;
+                let (side, hint) = (order.side, instruction.price_hint);
+                check_enter_book(side, price, remaining, order.time_in_force, hint, vacated)?;
+            }
+            modify_limit_order(&mut order, price, amount, instruction.price_hint);
+            Ok(order.id)
+        }
+        _ => Err(Error::InvalidInstruction),
+    }
+}
+
+// Order signed off-chain by `signer` and settled by `settle_orders`. Orders are
+// identified by the Blake2b hash of SIGNED_ORDER_DOMAIN, the signing domain and their
+// bytes, which is also the message that gets signed. Orders with a nonce below the
+// signer's current nonce are cancelled; an `expires_at` of zero never expires.
+#[derive(Clone)]
+struct SignedOrder {
+    signer: PublicKey,
+    side: u8,
+    amount: U512,
+    price: U512,
+    nonce: u64,
+    expires_at: u64,
+}
+
+impl CLTyped for SignedOrder {
+    fn cl_type() -> CLType {
+        CLType::Any
+    }
+}
+
+impl ToBytes for SignedOrder {
+    fn to_bytes(&self) -> Result<Vec<u8>, bytesrepr::Error> {
+        let mut result = bytesrepr::allocate_buffer(self)?;
+        result.extend(self.signer.to_bytes()?);
+        result.extend(self.side.to_bytes()?);
+        result.extend(self.amount.to_bytes()?);
+        result.extend(self.price.to_bytes()?);
+        result.extend(self.nonce.to_bytes()?);
+        result.extend(self.expires_at.to_bytes()?);
+        Ok(result)
+    }
+
+    fn serialized_length(&self) -> usize {
+        self.signer.serialized_length()
+            + self.side.serialized_length()
+            + self.amount.serialized_length()
+            + self.price.serialized_length()
+            + self.nonce.serialized_length()
+            + self.expires_at.serialized_length()
+    }
+}
+
+impl FromBytes for SignedOrder {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), bytesrepr::Error> {
+        let (signer, rem) = PublicKey::from_bytes(bytes)?;
+        let (side, rem) = u8::from_bytes(rem)?;
+        let (amount, rem) = U512::from_bytes(rem)?;
+        let (price, rem) = U512::from_bytes(rem)?;
+        let (nonce, rem) = u64::from_bytes(rem)?;
+        let (expires_at, rem) = u64::from_bytes(rem)?;
+        Ok((
+            SignedOrder {
+                signer,
+                side,
+                amount,
+                price,
+                nonce,
+                expires_at,
+            },
+            rem,
+        ))
+    }
+}
+
+fn signed_order_hash(order: &SignedOrder) -> [u8; 32] {
+    let domain: (String, Key) = get_key(KEY_SIGNING_DOMAIN);
+    let mut message = Vec::from(SIGNED_ORDER_DOMAIN);
+    message.extend(domain.to_bytes().unwrap_or_revert());
+    message.extend(order.to_bytes().unwrap_or_revert());
+    cryptography::generic_hash(message, HashAlgorithm::Blake2b)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for byte in bytes {
+        result.push_str(&format!("{:02x}", byte));
+    }
+    result
+}
+
+fn order_nonce(owner: &Key) -> u64 {
+    let dict = get_dict(DICT_ORDER_NONCES);
+    storage::dictionary_get(dict, &make_key(owner))
+        .unwrap_or_revert()
+        .unwrap_or(0)
+}
+
+fn signed_order_fill(hash: &str) -> U512 {
+    let dict = get_dict(DICT_SIGNED_FILLS);
+    storage::dictionary_get(dict, hash)
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero())
+}
+
+// Checks the signature, nonce and expiry of a signed order and returns its account,
+// hash and unfilled amount
+fn verify_signed_order(order: &SignedOrder, signature: &Signature) -> (Key, String, U512) {
+    let hash = signed_order_hash(order);
+    cryptography::verify_signature(hash, signature, &order.signer)
+        .unwrap_or_revert_with(Error::InvalidSignature);
+
+    let owner = Key::Account(order.signer.to_account_hash());
+    if order.nonce < order_nonce(&owner) {
+        runtime::revert(Error::InvalidNonce);
+    }
+    let now: u64 = runtime::get_blocktime().into();
+    if order.expires_at != 0 && now >= order.expires_at {
+        runtime::revert(Error::OrderExpired);
+    }
+    if order.side > SIDE_SELL {
+        runtime::revert(Error::InvalidSide);
+    }
+
+    let hash = hex(&hash);
+    let filled = signed_order_fill(&hash);
+    if filled >= order.amount {
+        runtime::revert(Error::OrderNotOpen);
+    }
+    (owner, hash, order.amount - filled)
+}
+
+// Token functions
+#[no_mangle]
+pub extern "C" fn name() {
+    let val: String = get_key(KEY_NAME);
+    runtime::ret(CLValue::from_t(val).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn symbol() {
+    let val: String = get_key(KEY_SYMBOL);
+    runtime::ret(CLValue::from_t(val).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn decimals() {
+    let val: u8 = get_key(KEY_DECIMALS);
+    runtime::ret(CLValue::from_t(val).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn total_supply() {
+    let val: U256 = get_key(KEY_TOTAL_SUPPLY);
+    runtime::ret(CLValue::from_t(val).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn balance_of() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let dict = get_dict(DICT_BALANCES);
+    let balance: U256 = storage::dictionary_get(dict, &make_key(&owner))
+        .unwrap_or_revert()
+        .unwrap_or(U256::zero());
+    runtime::ret(CLValue::from_t(balance).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn transfer() {
+    let recipient: Key = runtime::get_named_arg(ARG_RECIPIENT);
+    let amount: U256 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+    
+    let dict = get_dict(DICT_BALANCES);
+    let from_bal: U256 = storage::dictionary_get(dict, &make_key(&caller))
+        .unwrap_or_revert()
+        .unwrap_or(U256::zero());
+    
+    if from_bal < amount {
+        runtime::revert(Error::InsufficientBalance);
+    }
+    
+    let to_bal: U256 = storage::dictionary_get(dict, &make_key(&recipient))
+        .unwrap_or_revert()
+        .unwrap_or(U256::zero());
+    
+    storage::dictionary_put(dict, &make_key(&caller), from_bal - amount);
+    storage::dictionary_put(dict, &make_key(&recipient), to_bal + amount);
+}
+
+#[no_mangle]
+pub extern "C" fn approve() {
+    let spender: Key = runtime::get_named_arg(ARG_SPENDER);
+    let amount: U256 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+    
+    let dict = get_dict(DICT_ALLOWANCES);
+    let key = format!("{}_{}", make_key(&caller), make_key(&spender));
+    storage::dictionary_put(dict, &key, amount);
+}
+
+#[no_mangle]
+pub extern "C" fn allowance() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let spender: Key = runtime::get_named_arg(ARG_SPENDER);
+    
+    let dict = get_dict(DICT_ALLOWANCES);
+    let key = format!("{}_{}", make_key(&owner), make_key(&spender));
+    let val: U256 = storage::dictionary_get(dict, &key)
+        .unwrap_or_revert()
+        .unwrap_or(U256::zero());
+    runtime::ret(CLValue::from_t(val).unwrap_or_revert());
+}
+
+// Staking functions
+#[no_mangle]
+pub extern "C" fn stake() {
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+    
+    let dict = get_dict(DICT_STAKERS);
+    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    
+    let new_stake = current + amount;
+    storage::dictionary_put(dict, &make_key(&caller), new_stake);
+    
+    let total: U512 = get_key(KEY_TOTAL_STAKED);
+    set_key(KEY_TOTAL_STAKED, total + amount);
+}
+
+#[no_mangle]
+pub extern "C" fn unstake() {
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+    
+    let dict = get_dict(DICT_STAKERS);
+    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    
+    if current < amount {
+        runtime::revert(Error::InsufficientBalance);
+    }
+    
+    storage::dictionary_put(dict, &make_key(&caller), current - amount);
+    
+    let total: U512 = get_key(KEY_TOTAL_STAKED);
+    set_key(KEY_TOTAL_STAKED, total - amount);
+}
+
+#[no_mangle]
+pub extern "C" fn get_stake() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let dict = get_dict(DICT_STAKERS);
+    let stake: U512 = storage::dictionary_get(dict, &make_key(&owner))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    runtime::ret(CLValue::from_t(stake).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn stake_for_fees() {
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+    
+    if amount.is_zero() {
+        runtime::revert(Error::InvalidAmount);
+    }
+    
+    // Fee stakes leave the token balance, so the staking discount is backed by locked CFLOW
+    debit_tokens(&caller, to_u256(amount));
+    let dict = get_dict(DICT_FEE_STAKES);
+    storage::dictionary_put(dict, &make_key(&caller), fee_stake(&caller) + amount);
+    
+    let total: U512 = get_key(KEY_TOTAL_FEE_STAKED);
+    set_key(KEY_TOTAL_FEE_STAKED, total + amount);
+}
+
+#[no_mangle]
+pub extern "C" fn unstake_fees() {
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+    
+    let current = fee_stake(&caller);
+    if current < amount {
+        runtime::revert(Error::InsufficientBalance);
+    }
+    
+    let dict = get_dict(DICT_FEE_STAKES);
+    storage::dictionary_put(dict, &make_key(&caller), current - amount);
+    credit_tokens(&caller, to_u256(amount));
+    
+    let total: U512 = get_key(KEY_TOTAL_FEE_STAKED);
+    set_key(KEY_TOTAL_FEE_STAKED, total - amount);
+}
+
+#[no_mangle]
+pub extern "C" fn get_fee_stake() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    runtime::ret(CLValue::from_t(fee_stake(&owner)).unwrap_or_revert());
+}
+
+// Trading functions
+#[no_mangle]
+pub extern "C" fn open_position() {
+    let market_id: u64 = runtime::get_named_arg(ARG_MARKET_ID);
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let leverage: u32 = runtime::get_named_arg(ARG_LEVERAGE);
+    let side: u8 = runtime::get_named_arg(ARG_SIDE); // 0=long, 1=short
+    let caller = Key::from(runtime::get_caller());
+
+    if amount.is_zero() {
+        runtime::revert(Error::InvalidAmount);
+    }
+
+    if side != SIDE_LONG && side != SIDE_SHORT {
+        runtime::revert(Error::InvalidSide);
+    }
+
+    let market = read_market(market_id);
+    if !market.enabled {
+        runtime::revert(Error::MarketDisabled);
+    }
+
+    if leverage == 0 || leverage > market.max_leverage {
+        runtime::revert(Error::InvalidLeverage);
+    }
+
+    // Collateral must cover the initial margin of the leveraged notional
+    if u64::from(leverage) * u64::from(market.initial_margin_bps) > BPS_DENOMINATOR {
+        runtime::revert(Error::InsufficientMargin);
+    }
+
+    let state = accrue_funding(&market);
+    let size = amount * U512::from(leverage);
+    let price = execution_price(&market, &state, side, size, true);
+    check_execution(side, true, price);
+    let fee = trading_fee(&caller, size, market.taker_fee_bps);
+    debit_vault(&caller, amount + fee);
+    collect_fee(fee);
+
+    let counter: u64 = get_key(KEY_POSITION_COUNTER);
+    let position_id = counter + 1;
+    set_key(KEY_POSITION_COUNTER, position_id);
+
+    increase_open_interest(&market, side, size);
+    increase_account_exposure(&caller, size, true);
+
+    let position = Position {
+        id: position_id,
+        owner: caller,
+        market_id,
+        side,
+        collateral: amount,
+        size,
+        entry_price: price,
+        leverage,
+        entry_funding_index: state.funding_index,
+        opened_at: runtime::get_blocktime().into(),
+    };
+    index_position(&position);
+    write_position(&position);
+    record_trade(&position, TRADE_OPEN, size, price, amount, false);
+    record_trade(&position, TRADE_FEE, size, price, fee, false);
+
+    // Optional stop-loss and take-profit covering the whole position
+    let stop_loss_price: Option<U512> = runtime::try_get_named_arg(ARG_STOP_LOSS_PRICE);
+    if let Some(trigger_price) = stop_loss_price {
+        attach_position_order(&position, ORDER_KIND_STOP_LOSS, U512::zero(), trigger_price, 0);
+    }
+    let take_profit_price: Option<U512> = runtime::try_get_named_arg(ARG_TAKE_PROFIT_PRICE);
+    if let Some(trigger_price) = take_profit_price {
+        attach_position_order(&position, ORDER_KIND_TAKE_PROFIT, U512::zero(), trigger_price, 0);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn close_position() {
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+    let caller = Key::from(runtime::get_caller());
+
+    let position = read_position(&caller, position_id);
+    let size = position.size;
+    reduce_position(position, size);
+}
+
+#[no_mangle]
+pub extern "C" fn partial_close() {
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+    let size: U512 = runtime::get_named_arg(ARG_SIZE);
+    let caller = Key::from(runtime::get_caller());
+
+    let position = read_position(&caller, position_id);
+    if size.is_zero() || size > position.size {
+        runtime::revert(Error::InvalidAmount);
+    }
+    reduce_position(position, size);
+}
+
+// Closes `size` of notional at the impact-adjusted price, subject to the caller's
+// slippage and deadline bounds
+fn reduce_position(position: Position, size: U512) {
+    let market = read_market(position.market_id);
+    let state = accrue_funding(&market);
+    let price = execution_price(&market, &state, position.side, size, false);
+    check_execution(position.side, false, price);
+    settle_reduction(position, &market, &state, size, price, market.taker_fee_bps);
+}
+
+// Closes `size` of notional at `price`, realizing the proportional PnL into the
+// owner's vault balance and charging `fee_bps` of the closed notional
+fn settle_reduction(
+    mut position: Position,
+    market: &Market,
+    state: &MarketState,
+    size: U512,
+    price: U512,
+    fee_bps: u32,
+) {
+    settle_funding(&mut position, state);
+
+    let bankrupt = position.clone();
+    let slice = take_slice(&mut position, size);
+    let (equity, shortfall) = position_equity(&slice, price);
+    if !shortfall.is_zero() {
+        absorb_bad_debt(market, state, &bankrupt, shortfall, market_price(market.id));
+    }
+
+    let (profit, pnl) = realized_pnl(&slice, equity, shortfall);
+    record_trade(&slice, TRADE_CLOSE, size, price, pnl, profit);
+
+    let fee = trading_fee(&position.owner, size, fee_bps);
+    let fee = core::cmp::min(fee, equity);
+    collect_fee(fee);
+    record_trade(&slice, TRADE_FEE, size, price, fee, false);
+    credit_vault(&position.owner, equity - fee);
+    decrease_open_interest(market.id, position.side, size);
+    decrease_account_exposure(&position.owner, size, position.size.is_zero());
+
+    if position.size.is_zero() {
+        unindex_position(&position);
+        cancel_position_orders(&position);
+        position.collateral = U512::zero();
+    } else {
+        position.leverage = effective_leverage(&position);
+    }
+    write_position(&position);
+}
+
+#[no_mangle]
+pub extern "C" fn increase_position() {
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let leverage: u32 = runtime::get_named_arg(ARG_LEVERAGE);
+    let caller = Key::from(runtime::get_caller());
+
+    if amount.is_zero() {
+        runtime::revert(Error::InvalidAmount);
+    }
+
+    let mut position = read_position(&caller, position_id);
+    let market = read_market(position.market_id);
+    if !market.enabled {
+        runtime::revert(Error::MarketDisabled);
+    }
+
+    if leverage == 0 || leverage > market.max_leverage {
+        runtime::revert(Error::InvalidLeverage);
+    }
+
+    let state = accrue_funding(&market);
+    settle_funding(&mut position, &state);
+
+    let added_size = amount * U512::from(leverage);
+    let price = execution_price(&market, &state, position.side, added_size, true);
+    check_execution(position.side, true, price);
+    let fee = trading_fee(&caller, added_size, market.taker_fee_bps);
+    debit_vault(&caller, amount + fee);
+    collect_fee(fee);
+    increase_open_interest(&market, position.side, added_size);
+    increase_account_exposure(&caller, added_size, false);
+
+    // Blend entry prices so unrealized PnL of both legs is preserved
+    let total_size = position.size + added_size;
+    position.entry_price = total_size * position.entry_price * price
+        / (position.size * price + added_size * position.entry_price);
+    position.size = total_size;
+    position.collateral += amount;
+    position.leverage = effective_leverage(&position);
+
+    require_initial_margin(&market, &position, market_price(market.id));
+    write_position(&position);
+    record_trade(&position, TRADE_MODIFY, added_size, price, amount, false);
+    record_trade(&position, TRADE_FEE, added_size, price, fee, false);
+}
+
+#[no_mangle]
+pub extern "C" fn add_collateral() {
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+
+    if amount.is_zero() {
+        runtime::revert(Error::InvalidAmount);
+    }
+
+    let mut position = read_position(&caller, position_id);
+    let market = read_market(position.market_id);
+    let state = accrue_funding(&market);
+    settle_funding(&mut position, &state);
+
+    debit_vault(&caller, amount);
+    position.collateral += amount;
+    position.leverage = effective_leverage(&position);
+    write_position(&position);
+    record_trade(&position, TRADE_MODIFY, U512::zero(), state.price, amount, false);
+}
+
+#[no_mangle]
+pub extern "C" fn remove_collateral() {
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+
+    let mut position = read_position(&caller, position_id);
+    let market = read_market(position.market_id);
+    let state = accrue_funding(&market);
+    settle_funding(&mut position, &state);
+
+    if amount.is_zero() || amount > position.collateral {
+        runtime::revert(Error::InvalidAmount);
+    }
+
+    position.collateral -= amount;
+    position.leverage = effective_leverage(&position);
+    require_initial_margin(&market, &position, market_price(market.id));
+
+    credit_vault(&caller, amount);
+    write_position(&position);
+    record_trade(&position, TRADE_MODIFY, U512::zero(), state.price, amount, true);
+}
+
+#[no_mangle]
+pub extern "C" fn liquidate() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+    let liquidator = Key::from(runtime::get_caller());
+
+    let mut position = read_position(&owner, position_id);
+    let market = read_market(position.market_id);
+    let price = market_price(market.id);
+    let state = accrue_funding(&market);
+    settle_funding(&mut position, &state);
+    if !is_liquidatable(&market, &position, price) {
+        runtime::revert(Error::PositionNotLiquidatable);
+    }
+
+    // Penalty is charged on notional and capped by whatever equity is left
+    let (equity, shortfall) = position_equity(&position, price);
+    if !shortfall.is_zero() {
+        absorb_bad_debt(&market, &state, &position, shortfall, price);
+    }
+
+    let (profit, pnl) = realized_pnl(&position, equity, shortfall);
+    record_trade(&position, TRADE_LIQUIDATION, position.size, price, pnl, profit);
+
+    let penalty = core::cmp::min(bps(position.size, market.liquidation_penalty_bps), equity);
+    record_trade(&position, TRADE_FEE, position.size, price, penalty, false);
+
+    let reward_bps: u32 = get_key(KEY_LIQUIDATOR_REWARD_BPS);
+    let reward = bps(penalty, reward_bps);
+    credit_vault(&liquidator, reward);
+
+    fund_insurance(penalty - reward);
+
+    credit_vault(&owner, equity - penalty);
+    decrease_open_interest(market.id, position.side, position.size);
+    decrease_account_exposure(&owner, position.size, true);
+    unindex_position(&position);
+    cancel_position_orders(&position);
+
+    position.collateral = U512::zero();
+    position.size = U512::zero();
+    write_position(&position);
+
+    let mut record = read_keeper(&liquidator);
+    if is_active_keeper(&record) {
+        record.liquidations += 1;
+        record.fees_earned += reward;
+        write_keeper(&liquidator, &record);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_liquidation_price() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+
+    // Price at which equity drops to the maintenance margin
+    let position = read_position(&owner, position_id);
+    let market = read_market(position.market_id);
+    let maintenance = maintenance_margin(&market, &position);
+    let price = if position.collateral <= maintenance {
+        position.entry_price
+    } else {
+        let buffer = position.entry_price * (position.collateral - maintenance) / position.size;
+        if position.side == SIDE_LONG {
+            position.entry_price.saturating_sub(buffer)
+        } else {
+            position.entry_price + buffer
+        }
+    };
+    runtime::ret(CLValue::from_t(price).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_trade_history() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let offset: u64 = runtime::get_named_arg(ARG_OFFSET);
+    let limit: u64 = runtime::get_named_arg(ARG_LIMIT);
+
+    let page_end = offset.saturating_add(core::cmp::min(limit, MAX_PAGE_SIZE));
+    let end = core::cmp::min(trade_count(&owner), page_end);
+    let dict = get_dict(DICT_TRADE_HISTORY);
+    let records: Vec<TradeRecord> = (offset..end)
+        .map(|index| {
+            storage::dictionary_get(dict, &format!("{}_{}", make_key(&owner), index))
+                .unwrap_or_revert()
+                .unwrap_or_revert()
+        })
+        .collect();
+    runtime::ret(CLValue::from_t(records).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_trade_count() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    runtime::ret(CLValue::from_t(trade_count(&owner)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_adl_rank() {
+    // 1 is next in the auto-deleveraging queue; 0 means the position is not eligible
+    // or not in the side's next ADL window, which keeps the lookup bounded
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+
+    let position = read_position(&owner, position_id);
+    let price = market_price(position.market_id);
+    let (cursor, _, _) = adl_queue(position.market_id, position.side);
+    let candidates = adl_candidates(position.market_id, position.side, cursor, price);
+    let rank = candidates
+        .iter()
+        .position(|(_, other)| other.owner == owner && other.id == position_id)
+        .map_or(0, |index| index as u64 + 1);
+    runtime::ret(CLValue::from_t(rank).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn continue_adl() {
+    // Anyone can push carried-over bad debt through the next ADL window of a side
+    let market_id: u64 = runtime::get_named_arg(ARG_MARKET_ID);
+    let side: u8 = runtime::get_named_arg(ARG_SIDE);
+    let (_, pending, bankruptcy_price) = adl_queue(market_id, side);
+    if pending.is_zero() {
+        runtime::revert(Error::InvalidAmount);
+    }
+
+    // The insurance fund may have been refilled since the debt was carried over
+    let market = read_market(market_id);
+    let state = accrue_funding(&market);
+    let uncovered = cover_bad_debt(market_id, pending);
+    let price = market_price(market_id);
+    let residual = auto_deleverage(&market, &state, side, uncovered, bankruptcy_price, price);
+    if !residual.is_zero() {
+        emit_event(format!(
+            "adl_shortfall market_id={} residual={}",
+            market_id, residual
+        ));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_open_interest() {
+    // (long, short) notional; skew is the difference between the two
+    let market_id: u64 = runtime::get_named_arg(ARG_MARKET_ID);
+    let state = read_market_state(market_id);
+    runtime::ret(
+        CLValue::from_t((state.long_open_interest, state.short_open_interest)).unwrap_or_revert(),
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn update_funding() {
+    // Keeper entry point; open, close and liquidate also accrue funding on demand
+    let market_id: u64 = runtime::get_named_arg(ARG_MARKET_ID);
+    let caller = Key::from(runtime::get_caller());
+    let market = read_market(market_id);
+    accrue_funding(&market);
+
+    // Active keepers are paid from the treasury at most once per funding interval per
+    // market. Trades accrue funding too, so the reward keeps its own clock.
+    let now: u64 = runtime::get_blocktime().into();
+    let rewards = get_dict(DICT_FUNDING_REWARDS);
+    let last_reward: u64 = storage::dictionary_get(rewards, &format!("{}", market_id))
+        .unwrap_or_revert()
+        .unwrap_or(0);
+    let mut record = read_keeper(&caller);
+    if is_active_keeper(&record) && now.saturating_sub(last_reward) >= FUNDING_INTERVAL {
+        storage::dictionary_put(rewards, &format!("{}", market_id), now);
+        let reward: U512 = get_key(KEY_FUNDING_UPDATE_REWARD);
+        let treasury: U512 = get_key(KEY_TREASURY);
+        let reward = core::cmp::min(reward, treasury);
+        set_key(KEY_TREASURY, treasury - reward);
+        credit_vault(&caller, reward);
+
+        record.funding_updates += 1;
+        record.fees_earned += reward;
+        write_keeper(&caller, &record);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_funding_index() {
+    let market_id: u64 = runtime::get_named_arg(ARG_MARKET_ID);
+    let state = read_market_state(market_id);
+    runtime::ret(CLValue::from_t(state.funding_index).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn set_price() {
+    let market_id: u64 = runtime::get_named_arg(ARG_MARKET_ID);
+    let price: U512 = runtime::get_named_arg(ARG_PRICE);
+
+    // The spot pair has no registry entry and is priced by the admin
+    if market_id == SPOT_MARKET_ID {
+        require_admin();
+    } else {
+        let market = read_market(market_id);
+        let caller = Key::from(runtime::get_caller());
+        let admin: Key = get_key(KEY_ADMIN);
+        if caller != market.oracle && caller != admin {
+            runtime::revert(Error::Unauthorized);
+        }
+    }
+
+    if price.is_zero() {
+        runtime::revert(Error::InvalidAmount);
+    }
+
+    let mut state = read_market_state(market_id);
+    state.price = price;
+    write_market_state(market_id, &state);
+}
+
+#[no_mangle]
+pub extern "C" fn get_insurance_fund() {
+    let insurance: U512 = get_key(KEY_INSURANCE_FUND);
+    runtime::ret(CLValue::from_t(insurance).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn set_insurance_fee_share() {
+    require_admin();
+    let insurance_fee_share_bps: u32 = runtime::get_named_arg(ARG_INSURANCE_FEE_SHARE_BPS);
+    if u64::from(insurance_fee_share_bps) > BPS_DENOMINATOR {
+        runtime::revert(Error::InvalidMarginParams);
+    }
+    set_key(KEY_INSURANCE_FEE_SHARE_BPS, insurance_fee_share_bps);
+}
+
+#[no_mangle]
+pub extern "C" fn get_account_exposure() {
+    // (open notional, open position count)
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let notional_dict = get_dict(DICT_ACCOUNT_NOTIONAL);
+    let notional: U512 = storage::dictionary_get(notional_dict, &make_key(&owner))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    let count_dict = get_dict(DICT_ACCOUNT_POSITIONS);
+    let count: u32 = storage::dictionary_get(count_dict, &make_key(&owner))
+        .unwrap_or_revert()
+        .unwrap_or(0);
+    runtime::ret(CLValue::from_t((notional, count)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn set_account_limits() {
+    require_admin();
+    let max_account_notional: U512 = runtime::get_named_arg(ARG_MAX_ACCOUNT_NOTIONAL);
+    let max_positions_per_account: u32 = runtime::get_named_arg(ARG_MAX_POSITIONS_PER_ACCOUNT);
+    set_key(KEY_MAX_ACCOUNT_NOTIONAL, max_account_notional);
+    set_key(KEY_MAX_POSITIONS_PER_ACCOUNT, max_positions_per_account);
+}
+
+#[no_mangle]
+pub extern "C" fn set_liquidator_reward() {
+    require_admin();
+    let liquidator_reward_bps: u32 = runtime::get_named_arg(ARG_LIQUIDATOR_REWARD_BPS);
+    if u64::from(liquidator_reward_bps) > BPS_DENOMINATOR {
+        runtime::revert(Error::InvalidMarginParams);
+    }
+    set_key(KEY_LIQUIDATOR_REWARD_BPS, liquidator_reward_bps);
+}
+
+// Market registry functions
+#[no_mangle]
+pub extern "C" fn add_market() {
+    require_admin();
+    let base_symbol: String = runtime::get_named_arg(ARG_BASE_SYMBOL);
+    let quote_symbol: String = runtime::get_named_arg(ARG_QUOTE_SYMBOL);
+
+    let counter: u64 = get_key(KEY_MARKET_COUNTER);
+    let market_id = counter + 1;
+    set_key(KEY_MARKET_COUNTER, market_id);
+
+    let market = market_from_args(market_id, base_symbol, quote_symbol, true);
+    write_market(&market);
+    write_market_state(market_id, &MarketState::new(runtime::get_blocktime().into()));
+}
+
+#[no_mangle]
+pub extern "C" fn update_market() {
+    require_admin();
+    let market_id: u64 = runtime::get_named_arg(ARG_MARKET_ID);
+
+    let current = read_market(market_id);
+    let market = market_from_args(
+        market_id,
+        current.base_symbol,
+        current.quote_symbol,
+        current.enabled,
+    );
+    write_market(&market);
+}
+
+#[no_mangle]
+pub extern "C" fn set_market_enabled() {
+    require_admin();
+    let market_id: u64 = runtime::get_named_arg(ARG_MARKET_ID);
+    let enabled: bool = runtime::get_named_arg(ARG_ENABLED);
+
+    let mut market = read_market(market_id);
+    market.enabled = enabled;
+    write_market(&market);
+}
+
+#[no_mangle]
+pub extern "C" fn get_market() {
+    let market_id: u64 = runtime::get_named_arg(ARG_MARKET_ID);
+    let market = read_market(market_id);
+    runtime::ret(CLValue::from_t(market).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn list_markets() {
+    let counter: u64 = get_key(KEY_MARKET_COUNTER);
+    let markets: Vec<Market> = (1..=counter).map(read_market).collect();
+    runtime::ret(CLValue::from_t(markets).unwrap_or_revert());
+}
+
+// Vault functions
+#[no_mangle]
+pub extern "C" fn vault_deposit() {
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+    
+    let dict = get_dict(DICT_VAULT);
+    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    
+    storage::dictionary_put(dict, &make_key(&caller), current + amount);
+    
+    let total: U512 = get_key(KEY_VAULT_TOTAL);
+    set_key(KEY_VAULT_TOTAL, total + amount);
+}
+
+#[no_mangle]
+pub extern "C" fn vault_withdraw() {
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+    
+    let dict = get_dict(DICT_VAULT);
+    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    
+    if current < amount {
+        runtime::revert(Error::InsufficientBalance);
+    }
+    
+    storage::dictionary_put(dict, &make_key(&caller), current - amount);
+    
+    let total: U512 = get_key(KEY_VAULT_TOTAL);
+    set_key(KEY_VAULT_TOTAL, total - amount);
+}
+// Liquid Staking Derivatives functions
+#[no_mangle]
+pub extern "C" fn stake_for_liquid() {
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+    mint_stcspr(&caller, amount);
+}
+
+// Stakes `amount` CSPR for `owner` and mints the matching stCSPR
+fn mint_stcspr(owner: &Key, amount: U512) {
+    // Get current ratio (stCSPR per CSPR)
+    let ratio: U512 = get_key(KEY_LIQUID_STAKING_RATIO);
+    let stcspr_amount = if ratio == U512::zero() {
+        amount
+    } else {
+        amount * U512::from(1000000u64) / ratio
+    };
+    
+    // Update liquid staker balance
+    let dict = get_dict(DICT_LIQUID_STAKERS);
+    let current: U512 = storage::dictionary_get(dict, &make_key(owner))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    storage::dictionary_put(dict, &make_key(owner), current + amount);
+    
+    // Mint stCSPR tokens
+    let stcspr_dict = get_dict(DICT_STCSPR_BALANCES);
+    let stcspr_balance: U512 = storage::dictionary_get(stcspr_dict, &make_key(owner))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    storage::dictionary_put(stcspr_dict, &make_key(owner), stcspr_balance + stcspr_amount);
+    
+    // Update totals
+    let total_liquid: U512 = get_key(KEY_TOTAL_LIQUID_STAKED);
+    set_key(KEY_TOTAL_LIQUID_STAKED, total_liquid + amount);
+    
+    let total_staked: U512 = get_key(KEY_TOTAL_STAKED);
+    set_key(KEY_TOTAL_STAKED, total_staked + amount);
+}
+
+#[no_mangle]
+pub extern "C" fn unstake_liquid() {
+    let stcspr_amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+    
+    // Get current ratio
+    let ratio: U512 = get_key(KEY_LIQUID_STAKING_RATIO);
+    let cspr_amount = if ratio == U512::zero() {
+        stcspr_amount
+    } else {
+        stcspr_amount * ratio / U512::from(1000000u64)
+    };
+    
+    // Burn stCSPR tokens
+    let stcspr_dict = get_dict(DICT_STCSPR_BALANCES);
+    let stcspr_balance: U512 = storage::dictionary_get(stcspr_dict, &make_key(&caller))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    
+    if stcspr_balance < stcspr_amount {
+        runtime::revert(ApiError::from(Error::InsufficientBalance));
+    }
+    
+    storage::dictionary_put(stcspr_dict, &make_key(&caller), stcspr_balance - stcspr_amount);
+    
+    // Update liquid staker balance
+    let dict = get_dict(DICT_LIQUID_STAKERS);
+    let current: U512 = storage::dictionary_get(dict, &make_key(&caller))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    storage::dictionary_put(dict, &make_key(&caller), current - cspr_amount);
+    
+    // Update totals
+    let total_liquid: U512 = get_key(KEY_TOTAL_LIQUID_STAKED);
+    set_key(KEY_TOTAL_LIQUID_STAKED, total_liquid - cspr_amount);
+    
+    let total_staked: U512 = get_key(KEY_TOTAL_STAKED);
+    set_key(KEY_TOTAL_STAKED, total_staked - cspr_amount);
+}
+
+// stCSPR price in CSPR, scaled like spot prices
+fn stcspr_price() -> U512 {
+    let ratio: U512 = get_key(KEY_LIQUID_STAKING_RATIO);
+    if ratio.is_zero() {
+        U512::from(PRICE_PRECISION)
+    } else {
+        ratio * U512::from(PRICE_PRECISION) / U512::from(1000000u64)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_liquid_stake_ratio() {
+    let ratio: U512 = get_key(KEY_LIQUID_STAKING_RATIO);
+    runtime::ret(CLValue::from_t(ratio).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_stcspr_balance() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let dict = get_dict(DICT_STCSPR_BALANCES);
+    let balance: U512 = storage::dictionary_get(dict, &make_key(&owner))
+        .unwrap_or_revert()
+        .unwrap_or(U512::zero());
+    runtime::ret(CLValue::from_t(balance).unwrap_or_revert());
+}
+
+// Limit Orders & Stop Loss functions
+#[no_mangle]
+pub extern "C" fn create_limit_order() {
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let price: U512 = runtime::get_named_arg(ARG_PRICE);
+    let order_type: u8 = runtime::get_named_arg(ARG_ORDER_TYPE); // 0=buy, 1=sell
+    let (time_in_force, expires_at) = time_in_force_args();
+    let price_hint: U512 = runtime::try_get_named_arg(ARG_PRICE_HINT).unwrap_or(U512::zero());
+    let caller = Key::from(runtime::get_caller());
+
+    check_limit_order(order_type, amount, price, time_in_force, expires_at)
+        .unwrap_or_else(|error| runtime::revert(error));
+    place_limit_order(caller, order_type, amount, price, time_in_force, expires_at, price_hint);
+}
+
+#[no_mangle]
+pub extern "C" fn place_order() {
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let price: U512 = runtime::get_named_arg(ARG_PRICE);
+    let side: u8 = runtime::get_named_arg(ARG_SIDE);
+    let (time_in_force, expires_at) = time_in_force_args();
+    let price_hint: U512 = runtime::try_get_named_arg(ARG_PRICE_HINT).unwrap_or(U512::zero());
+    let caller = Key::from(runtime::get_caller());
+
+    check_limit_order(side, amount, price, time_in_force, expires_at)
+        .unwrap_or_else(|error| runtime::revert(error));
+    let order =
+        place_limit_order(caller, side, amount, price, time_in_force, expires_at, price_hint);
+    runtime::ret(CLValue::from_t(order.id).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn create_stop_loss() {
+    create_position_order(ORDER_KIND_STOP_LOSS);
+}
+
+#[no_mangle]
+pub extern "C" fn create_take_profit() {
+    create_position_order(ORDER_KIND_TAKE_PROFIT);
+}
+
+#[no_mangle]
+pub extern "C" fn create_trailing_stop() {
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+    let size: U512 = runtime::get_named_arg(ARG_SIZE);
+    let trail_amount: U512 = runtime::get_named_arg(ARG_TRAIL_AMOUNT);
+    let trail_bps: u32 = runtime::get_named_arg(ARG_TRAIL_BPS);
+    let expires_at: u64 = runtime::try_get_named_arg(ARG_EXPIRES_AT).unwrap_or(0);
+    let caller = Key::from(runtime::get_caller());
+
+    // Exactly one of a fixed delta or a percentage below 100%
+    if trail_amount.is_zero() == (trail_bps == 0) || u64::from(trail_bps) >= BPS_DENOMINATOR {
+        runtime::revert(Error::InvalidTriggerPrice);
+    }
+
+    let position = read_position(&caller, position_id);
+    let mut order = new_position_order(&position, ORDER_KIND_TRAILING_STOP, size, expires_at);
+    order.trail_amount = trail_amount;
+    order.trail_bps = trail_bps;
+    order.price = market_price(position.market_id);
+    order.trigger_price = trailing_trigger(&order);
+    store_position_order(&position, &order);
+}
+
+#[no_mangle]
+pub extern "C" fn update_trailing_stop() {
+    require_keeper();
+    let order_id: u64 = runtime::get_named_arg(ARG_ORDER_ID);
+
+    let mut order = read_order(order_id);
+    if order.kind != ORDER_KIND_TRAILING_STOP {
+        runtime::revert(Error::InvalidOrderKind);
+    }
+    if order.status != ORDER_STATUS_OPEN {
+        runtime::revert(Error::OrderNotOpen);
+    }
+    if is_expired(&order) {
+        runtime::revert(Error::OrderExpired);
+    }
+
+    let price = market_price(order.market_id);
+    if trail_stop(&mut order, price) {
+        order.updated_at = runtime::get_blocktime().into();
+        write_order(&order);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn create_oco() {
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+    let size: U512 = runtime::get_named_arg(ARG_SIZE);
+    let stop_loss_price: U512 = runtime::get_named_arg(ARG_STOP_LOSS_PRICE);
+    let take_profit_price: U512 = runtime::get_named_arg(ARG_TAKE_PROFIT_PRICE);
+    let expires_at: u64 = runtime::try_get_named_arg(ARG_EXPIRES_AT).unwrap_or(0);
+    let caller = Key::from(runtime::get_caller());
+
+    let position = read_position(&caller, position_id);
+    let mut stop_loss = new_position_order(&position, ORDER_KIND_STOP_LOSS, size, expires_at);
+    let mut take_profit = new_position_order(&position, ORDER_KIND_TAKE_PROFIT, size, expires_at);
+    stop_loss.trigger_price = stop_loss_price;
+    take_profit.trigger_price = take_profit_price;
+    stop_loss.linked_order_id = take_profit.id;
+    take_profit.linked_order_id = stop_loss.id;
+
+    store_position_order(&position, &stop_loss);
+    store_position_order(&position, &take_profit);
+}
+
+#[no_mangle]
+pub extern "C" fn create_recurring_order() {
+    let asset: u8 = runtime::get_named_arg(ARG_ASSET);
+    let side: u8 = runtime::get_named_arg(ARG_SIDE);
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let slice_amount: U512 = runtime::get_named_arg(ARG_SLICE_AMOUNT);
+    let interval: u64 = runtime::get_named_arg(ARG_INTERVAL);
+    let min_price: U512 = runtime::get_named_arg(ARG_MIN_PRICE);
+    let max_price: U512 = runtime::get_named_arg(ARG_MAX_PRICE);
+    let caller = Key::from(runtime::get_caller());
+
+    if amount.is_zero() || slice_amount.is_zero() || slice_amount > amount || interval == 0 {
+        runtime::revert(Error::InvalidAmount);
+    }
+    if !max_price.is_zero() && min_price > max_price {
+        runtime::revert(Error::InvalidTriggerPrice);
+    }
+    // stCSPR is only bought, by staking the slice
+    if asset > ASSET_STCSPR {
+        runtime::revert(Error::InvalidOrderKind);
+    }
+    if side > SIDE_SELL || (asset == ASSET_STCSPR && side == SIDE_SELL) {
+        runtime::revert(Error::InvalidSide);
+    }
+
+    let zero = U512::zero();
+    let mut order = new_order(caller, side, ORDER_KIND_RECURRING, amount, zero, zero);
+    order.asset = asset;
+    order.slice_amount = slice_amount;
+    order.interval = interval;
+    order.next_execution_at = order.created_at;
+    order.min_price = min_price;
+    order.max_price = max_price;
+    lock_escrow(&mut order, amount);
+    let slices = (amount + slice_amount - U512::one()) / slice_amount;
+    if slices > U512::from(u64::MAX) {
+        runtime::revert(Error::InvalidAmount);
+    }
+    lock_execution_fee(&mut order, slices.as_u64());
+    write_order(&order);
+    index_order(&order);
+}
+
+#[no_mangle]
+pub extern "C" fn execute_slice() {
+    require_keeper();
+    let order_id: u64 = runtime::get_named_arg(ARG_ORDER_ID);
+    let keeper = Key::from(runtime::get_caller());
+
+    let mut order = read_order(order_id);
+    if order.kind != ORDER_KIND_RECURRING {
+        runtime::revert(Error::InvalidOrderKind);
+    }
+    if order.status != ORDER_STATUS_OPEN {
+        runtime::revert(Error::OrderNotOpen);
+    }
+    let now: u64 = runtime::get_blocktime().into();
+    if now < order.next_execution_at {
+        runtime::revert(Error::SliceNotDue);
+    }
+
+    let price = if order.asset == ASSET_STCSPR {
+        stcspr_price()
+    } else {
+        market_price(SPOT_MARKET_ID)
+    };
+    if price < order.min_price || (!order.max_price.is_zero() && price > order.max_price) {
+        runtime::revert(Error::PriceNotReached);
+    }
+
+    // Slices are measured in the escrowed asset: CSPR for buys, CFLOW for sells
+    let slice = core::cmp::min(order.slice_amount, order.amount - order.filled_amount);
+    consume_escrow(&mut order, slice);
+    if order.asset == ASSET_STCSPR {
+        mint_stcspr(&order.owner, slice);
+    } else if order.side == SIDE_BUY {
+        let (_, taker_fee_bps, _) = spot_fee_schedule();
+        let fee = trading_fee(&order.owner, slice, taker_fee_bps);
+        let cflow = (slice - fee) * U512::from(PRICE_PRECISION) / price;
+        if cflow.is_zero() {
+            runtime::revert(Error::InvalidAmount);
+        }
+        credit_vault(&keeper, slice - fee);
+        credit_treasury(fee);
+        debit_tokens(&keeper, to_u256(cflow));
+        credit_tokens(&order.owner, to_u256(cflow));
+    } else {
+        let (_, taker_fee_bps, _) = spot_fee_schedule();
+        let quote = quote_amount(slice, price);
+        if quote.is_zero() {
+            runtime::revert(Error::InvalidAmount);
+        }
+        let fee = trading_fee(&order.owner, quote, taker_fee_bps);
+        credit_tokens(&keeper, to_u256(slice));
+        debit_vault(&keeper, quote);
+        credit_vault(&order.owner, quote - fee);
+        credit_treasury(fee);
+    }
+
+    order.filled_amount += slice;
+    order.next_execution_at = now + order.interval;
+    pay_execution_fee(&mut order, &keeper);
+    emit_event(format!(
+        "recurring_slice_executed order_id={} amount={} price={} filled={}",
+        order.id, slice, price, order.filled_amount
+    ));
+
+    if order.filled_amount == order.amount {
+        finish_order(&mut order, ORDER_STATUS_EXECUTED);
+    } else {
+        order.updated_at = now;
+        write_order(&order);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_position_orders() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let position_id: u64 = runtime::get_named_arg(ARG_POSITION_ID);
+    let orders = position_orders(&owner, position_id);
+    runtime::ret(CLValue::from_t(orders).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn cancel_order() {
+    let order_id: u64 = runtime::get_named_arg(ARG_ORDER_ID);
+    let caller = Key::from(runtime::get_caller());
+
+    let mut order = read_cancellable_order(order_id, &caller);
+    cancel_open_order(&mut order);
+}
+
+#[no_mangle]
+pub extern "C" fn cancel_orders() {
+    let order_ids: Vec<u64> = runtime::get_named_arg(ARG_ORDER_IDS);
+    let caller = Key::from(runtime::get_caller());
+
+    if order_ids.len() as u64 > MAX_PAGE_SIZE {
+        runtime::revert(Error::TooManyOrders);
+    }
+
+    // All-or-nothing: any invalid id reverts the whole batch
+    for order_id in order_ids {
+        let mut order = read_cancellable_order(order_id, &caller);
+        cancel_open_order(&mut order);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn modify_order() {
+    let order_id: u64 = runtime::get_named_arg(ARG_ORDER_ID);
+    let new_price: U512 = runtime::get_named_arg(ARG_NEW_PRICE);
+    let new_amount: U512 = runtime::get_named_arg(ARG_NEW_AMOUNT);
+    let price_hint: U512 = runtime::try_get_named_arg(ARG_PRICE_HINT).unwrap_or(U512::zero());
+    let caller = Key::from(runtime::get_caller());
+
+    let mut order = read_cancellable_order(order_id, &caller);
+    check_modify(&order, new_price, new_amount).unwrap_or_else(|error| runtime::revert(error));
+    modify_limit_order(&mut order, new_price, new_amount, price_hint);
+}
+
+#[no_mangle]
+pub extern "C" fn batch_orders() {
+    let instructions: Vec<OrderInstruction> = runtime::get_named_arg(ARG_INSTRUCTIONS);
+    let atomic: bool = runtime::try_get_named_arg(ARG_ATOMIC).unwrap_or(true);
+    let caller = Key::from(runtime::get_caller());
+
+    if instructions.len() as u64 > MAX_PAGE_SIZE {
+        runtime::revert(Error::TooManyOrders);
+    }
+
+    // One (order id, error code) per instruction, code 0 when it was applied. Atomic
+    // batches revert on the first invalid instruction; otherwise it is skipped.
+    let mut results: Vec<(u64, u32)> = Vec::new();
+    for instruction in instructions.iter() {
+        let result = match apply_instruction(instruction, &caller) {
+            Ok(order_id) => (order_id, 0),
+            Err(error) if atomic => runtime::revert(error),
+            Err(error) => (instruction.order_id, error as u32),
+        };
+        results.push(result);
+    }
+    runtime::ret(CLValue::from_t(results).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn cancel_all() {
+    // Accounts can only cancel their own orders
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let caller = Key::from(runtime::get_caller());
+    if caller != owner {
+        runtime::revert(Error::Unauthorized);
+    }
+
+    // Cancels up to MAX_PAGE_SIZE orders per call and returns how many remain open.
+    // Cancelling one half of an OCO pair also closes the other, so the open set is
+    // re-read after every cancel instead of walking precomputed slots.
+    for _ in 0..MAX_PAGE_SIZE {
+        let count = open_order_count(&owner);
+        if count == 0 {
+            break;
+        }
+        let mut order = read_order(open_order_at(&owner, count - 1));
+        cancel_open_order(&mut order);
+    }
+
+    runtime::ret(CLValue::from_t(open_order_count(&owner)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn execute_order() {
+    require_keeper();
+    let order_id: u64 = runtime::get_named_arg(ARG_ORDER_ID);
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let keeper = Key::from(runtime::get_caller());
+
+    let mut order = read_order(order_id);
+    if order.owner != owner {
+        runtime::revert(Error::OrderNotFound);
+    }
+
+    if order.status != ORDER_STATUS_OPEN {
+        runtime::revert(Error::OrderNotOpen);
+    }
+
+    if is_expired(&order) {
+        runtime::revert(Error::OrderExpired);
+    }
+
+    // Recurring orders are executed slice by slice through execute_slice
+    if order.kind == ORDER_KIND_RECURRING {
+        runtime::revert(Error::InvalidOrderKind);
+    }
+
+    let oracle_price = market_price(order.market_id);
+    if order.kind != ORDER_KIND_LIMIT {
+        // Stop orders close their position at the oracle price
+        if order.kind == ORDER_KIND_TRAILING_STOP {
+            trail_stop(&mut order, oracle_price);
+        }
+        if !is_triggered(&order, oracle_price) {
+            runtime::revert(Error::PriceNotReached);
+        }
+        let position = read_position(&order.owner, order.position_id);
+        let size = if order.amount.is_zero() {
+            position.size
+        } else {
+            core::cmp::min(order.amount, position.size)
+        };
+        order.filled_amount = size;
+        pay_execution_fee(&mut order, &keeper);
+        finish_order(&mut order, ORDER_STATUS_EXECUTED);
+
+        // Take-profits rest until the price comes to them and pay the maker fee;
+        // stop-losses and trailing stops close into the move and pay the taker fee
+        let market = read_market(position.market_id);
+        let state = accrue_funding(&market);
+        let fee_bps = if order.kind == ORDER_KIND_TAKE_PROFIT {
+            market.maker_fee_bps
+        } else {
+            market.taker_fee_bps
+        };
+        settle_reduction(position, &market, &state, size, oracle_price, fee_bps);
+        return;
+    }
+
+    // Limit orders fill at their limit price once the oracle crosses it
+    let crossed = if order.side == SIDE_BUY {
+        oracle_price <= order.price
+    } else {
+        oracle_price >= order.price
+    };
+    if !crossed {
+        runtime::revert(Error::PriceNotReached);
+    }
+
+    remove_from_book(&order);
+    let amount = order.amount - order.filled_amount;
+    let price = order.price;
+    settle_spot_fill(&mut order, &keeper, amount, price);
+    pay_execution_fee(&mut order, &keeper);
+    finish_order(&mut order, ORDER_STATUS_EXECUTED);
+}
+
+#[no_mangle]
+pub extern "C" fn reclaim_expired_order() {
+    let order_id: u64 = runtime::get_named_arg(ARG_ORDER_ID);
+    let caller = Key::from(runtime::get_caller());
+
+    let mut order = read_order(order_id);
+    if order.status != ORDER_STATUS_OPEN {
+        runtime::revert(Error::OrderNotOpen);
+    }
+    if !is_expired(&order) {
+        runtime::revert(Error::OrderNotExpired);
+    }
+
+    // The caller earns a share of the remaining escrow, paid in the escrowed asset
+    let bounty_bps: u32 = get_key(KEY_EXPIRY_BOUNTY_BPS);
+    let bounty = bps(order.escrow, bounty_bps);
+    consume_escrow(&mut order, bounty);
+    if order.side == SIDE_BUY {
+        credit_vault(&caller, bounty);
+    } else {
+        credit_tokens(&caller, to_u256(bounty));
+    }
+
+    // ...plus one reserved execution fee, which is all position orders escrow
+    let fee = core::cmp::min(order.execution_fee, order.fee_escrow);
+    take_fee_escrow(&mut order, fee);
+    credit_vault(&caller, fee);
+
+    if order.kind == ORDER_KIND_LIMIT {
+        remove_from_book(&order);
+    }
+    finish_order(&mut order, ORDER_STATUS_EXPIRED);
+}
+
+#[no_mangle]
+pub extern "C" fn set_expiry_bounty() {
+    require_admin();
+    let expiry_bounty_bps: u32 = runtime::get_named_arg(ARG_EXPIRY_BOUNTY_BPS);
+    if u64::from(expiry_bounty_bps) > BPS_DENOMINATOR {
+        runtime::revert(Error::InvalidMarginParams);
+    }
+    set_key(KEY_EXPIRY_BOUNTY_BPS, expiry_bounty_bps);
+}
+
+#[no_mangle]
+pub extern "C" fn get_locked_balance() {
+    // (CSPR locked in buy orders, CFLOW locked in sell orders)
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let cspr = locked_balance(DICT_LOCKED_CSPR, &owner);
+    let cflow = to_u256(locked_balance(DICT_LOCKED_CFLOW, &owner));
+    runtime::ret(CLValue::from_t((cspr, cflow)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn set_keeper() {
+    require_admin();
+    let keeper: Key = runtime::get_named_arg(ARG_KEEPER);
+    let enabled: bool = runtime::get_named_arg(ARG_ENABLED);
+
+    // Suspends or reinstates a bonded keeper
+    let mut record = read_keeper(&keeper);
+    record.suspended = !enabled;
+    write_keeper(&keeper, &record);
+}
+
+#[no_mangle]
+pub extern "C" fn register_keeper() {
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let caller = Key::from(runtime::get_caller());
+
+    // Adds to the bond; registering again also calls off a pending exit
+    debit_vault(&caller, amount);
+    let mut record = read_keeper(&caller);
+    record.bond += amount;
+    record.unbonding_at = 0;
+    let min_bond: U512 = get_key(KEY_MIN_KEEPER_BOND);
+    if record.bond.is_zero() || record.bond < min_bond {
+        runtime::revert(Error::KeeperBondTooLow);
+    }
+    write_keeper(&caller, &record);
+}
+
+#[no_mangle]
+pub extern "C" fn request_keeper_exit() {
+    let caller = Key::from(runtime::get_caller());
+    let mut record = read_keeper(&caller);
+    if record.bond.is_zero() {
+        runtime::revert(Error::InsufficientBalance);
+    }
+    let now: u64 = runtime::get_blocktime().into();
+    record.unbonding_at = now + KEEPER_UNBONDING_PERIOD;
+    write_keeper(&caller, &record);
+}
+
+#[no_mangle]
+pub extern "C" fn withdraw_keeper_bond() {
+    let caller = Key::from(runtime::get_caller());
+    let mut record = read_keeper(&caller);
+    let now: u64 = runtime::get_blocktime().into();
+    if record.unbonding_at == 0 || now < record.unbonding_at {
+        runtime::revert(Error::KeeperUnbonding);
+    }
+
+    credit_vault(&caller, record.bond);
+    record.bond = U512::zero();
+    record.unbonding_at = 0;
+    write_keeper(&caller, &record);
+}
+
+#[no_mangle]
+pub extern "C" fn slash_keeper() {
+    require_admin();
+    // Governance action: invalid executions revert on-chain, so slashing covers
+    // misbehaviour proven off-chain and is recorded with its reason and evidence
+    let keeper: Key = runtime::get_named_arg(ARG_KEEPER);
+    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
+    let reason: String = runtime::get_named_arg(ARG_REASON);
+    let evidence: String = runtime::get_named_arg(ARG_EVIDENCE);
+
+    // Slashed bond goes to the insurance fund
+    let mut record = read_keeper(&keeper);
+    if amount.is_zero() || amount > record.bond || reason.is_empty() {
+        runtime::revert(Error::InvalidAmount);
+    }
+    let slashes = get_dict(DICT_KEEPER_SLASHES);
+    let key = format!("{}_{}", make_key(&keeper), record.slash_count);
+    storage::dictionary_put(slashes, &key, (amount, reason.clone(), evidence.clone()));
+    record.bond -= amount;
+    record.slashed += amount;
+    record.slash_count += 1;
+    write_keeper(&keeper, &record);
+    fund_insurance(amount);
+
+    emit_event(format!(
+        "keeper_slashed keeper={} amount={} reason={} evidence={}",
+        make_key(&keeper),
+        amount,
+        reason,
+        evidence
+    ));
+}
+
+#[no_mangle]
+pub extern "C" fn get_keeper_slash() {
+    // (amount, reason, evidence) of a keeper's `index`th slash
+    let keeper: Key = runtime::get_named_arg(ARG_KEEPER);
+    let index: u64 = runtime::get_named_arg(ARG_INDEX);
+    let slashes = get_dict(DICT_KEEPER_SLASHES);
+    let slash: (U512, String, String) =
+        storage::dictionary_get(slashes, &format!("{}_{}", make_key(&keeper), index))
+            .unwrap_or_revert()
+            .unwrap_or_revert_with(Error::InvalidAmount);
+    runtime::ret(CLValue::from_t(slash).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn set_keeper_params() {
+    require_admin();
+    let min_keeper_bond: U512 = runtime::get_named_arg(ARG_MIN_KEEPER_BOND);
+    let execution_fee: U512 = runtime::get_named_arg(ARG_EXECUTION_FEE);
+    let funding_update_reward: U512 = runtime::get_named_arg(ARG_FUNDING_UPDATE_REWARD);
+    set_key(KEY_MIN_KEEPER_BOND, min_keeper_bond);
+    set_key(KEY_EXECUTION_FEE, execution_fee);
+    set_key(KEY_FUNDING_UPDATE_REWARD, funding_update_reward);
+}
+
+#[no_mangle]
+pub extern "C" fn keeper_stats() {
+    let keeper: Key = runtime::get_named_arg(ARG_KEEPER);
+    let record = read_keeper(&keeper);
+    runtime::ret(CLValue::from_t(record).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn settle_orders() {
+    let maker_order: SignedOrder = runtime::get_named_arg(ARG_MAKER_ORDER);
+    let taker_order: SignedOrder = runtime::get_named_arg(ARG_TAKER_ORDER);
+    let signatures: Vec<Signature> = runtime::get_named_arg(ARG_SIGNATURES);
+
+    // Signatures are (maker, taker); anyone can relay a matching pair
+    if signatures.len() != 2 {
+        runtime::revert(Error::InvalidSignature);
+    }
+    let (maker, maker_hash, maker_remaining) = verify_signed_order(&maker_order, &signatures[0]);
+    let (taker, taker_hash, taker_remaining) = verify_signed_order(&taker_order, &signatures[1]);
+    if maker_order.side == taker_order.side {
+        runtime::revert(Error::InvalidSide);
+    }
+
+    let (buy, sell) = if maker_order.side == SIDE_BUY {
+        (&maker_order, &taker_order)
+    } else {
+        (&taker_order, &maker_order)
+    };
+    if buy.price < sell.price {
+        runtime::revert(Error::PriceNotReached);
+    }
+
+    // Fills at the maker's price, paid straight from the two balances
+    let amount = core::cmp::min(maker_remaining, taker_remaining);
+    let price = maker_order.price;
+    let quote = quote_amount(amount, price);
+    if quote.is_zero() {
+        runtime::revert(Error::InvalidAmount);
+    }
+    let (maker_fee_bps, taker_fee_bps, rebate_bps) = spot_fee_schedule();
+    let maker_fee = trading_fee(&maker, quote, maker_fee_bps);
+    let taker_fee = trading_fee(&taker, quote, taker_fee_bps);
+    let (buyer, seller, buyer_fee, seller_fee) = if maker_order.side == SIDE_BUY {
+        (maker, taker, maker_fee, taker_fee)
+    } else {
+        (taker, maker, taker_fee, maker_fee)
+    };
+    let rebate = core::cmp::min(bps(quote, rebate_bps), maker_fee + taker_fee);
+    debit_vault(&buyer, quote + buyer_fee);
+    credit_vault(&seller, quote - seller_fee);
+    debit_tokens(&seller, to_u256(amount));
+    credit_tokens(&buyer, to_u256(amount));
+    credit_vault(&maker, rebate);
+    credit_treasury(maker_fee + taker_fee - rebate);
+
+    let dict = get_dict(DICT_SIGNED_FILLS);
+    let maker_filled = maker_order.amount - maker_remaining + amount;
+    let taker_filled = taker_order.amount - taker_remaining + amount;
+    storage::dictionary_put(dict, &maker_hash, maker_filled);
+    storage::dictionary_put(dict, &taker_hash, taker_filled);
+
+    emit_event(format!(
+        "signed_orders_settled maker_hash={} taker_hash={} amount={} price={}",
+        maker_hash, taker_hash, amount, price
+    ));
+}
+
+#[no_mangle]
+pub extern "C" fn cancel_signed_orders() {
+    // Invalidates every signed order of the caller with a lower nonce
+    let nonce: u64 = runtime::get_named_arg(ARG_NONCE);
+    let caller = Key::from(runtime::get_caller());
+    if nonce <= order_nonce(&caller) {
+        runtime::revert(Error::InvalidNonce);
+    }
+    let dict = get_dict(DICT_ORDER_NONCES);
+    storage::dictionary_put(dict, &make_key(&caller), nonce);
+}
+
+#[no_mangle]
+pub extern "C" fn get_signing_domain() {
+    // (chain name, contract package hash) signed order messages are bound to
+    let domain: (String, Key) = get_key(KEY_SIGNING_DOMAIN);
+    runtime::ret(CLValue::from_t(domain).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_order_nonce() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    runtime::ret(CLValue::from_t(order_nonce(&owner)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_signed_order_fill() {
+    let order_hash: String = runtime::get_named_arg(ARG_ORDER_HASH);
+    runtime::ret(CLValue::from_t(signed_order_fill(&order_hash)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_orders() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let status_filter: u8 = runtime::get_named_arg(ARG_STATUS_FILTER);
+    let offset: u64 = runtime::get_named_arg(ARG_OFFSET);
+    let limit: u64 = runtime::get_named_arg(ARG_LIMIT);
+
+    // Open orders page through the open set; any other filter pages through the
+    // full history and drops non-matching orders from the page
+    let open = status_filter == ORDER_STATUS_OPEN;
+    let orders: Vec<Order> = owner_order_ids(&owner, open, offset, limit)
+        .into_iter()
+        .map(read_order)
+        .filter(|order| status_filter == ORDER_STATUS_ANY || order.status == status_filter)
+        .collect();
+    runtime::ret(CLValue::from_t(orders).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_open_order_count() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    runtime::ret(CLValue::from_t(open_order_count(&owner)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_order_count() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    runtime::ret(CLValue::from_t(owner_order_count(&owner)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn set_spot_fees() {
+    require_admin();
+    let maker_fee_bps: u32 = runtime::get_named_arg(ARG_MAKER_FEE_BPS);
+    let taker_fee_bps: u32 = runtime::get_named_arg(ARG_TAKER_FEE_BPS);
+    let maker_rebate_bps: u32 = runtime::get_named_arg(ARG_MAKER_REBATE_BPS);
+    // Makers either pay a fee or earn a rebate, and rebates are funded by taker fees.
+    // Buy escrow only reserves the taker fee, so a resting buy must never owe more as maker
+    if (maker_fee_bps > 0 && maker_rebate_bps > 0)
+        || maker_fee_bps > taker_fee_bps
+        || maker_rebate_bps > taker_fee_bps
+        || u64::from(maker_fee_bps) > BPS_DENOMINATOR
+        || u64::from(taker_fee_bps) > BPS_DENOMINATOR
+    {
+        runtime::revert(Error::InvalidMarginParams);
+    }
+    set_key(KEY_SPOT_MAKER_FEE_BPS, maker_fee_bps);
+    set_key(KEY_SPOT_TAKER_FEE_BPS, taker_fee_bps);
+    set_key(KEY_SPOT_MAKER_REBATE_BPS, maker_rebate_bps);
+}
+
+#[no_mangle]
+pub extern "C" fn get_spot_fees() {
+    // (maker fee, taker fee, maker rebate) in bps
+    runtime::ret(CLValue::from_t(spot_fee_schedule()).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn set_volume_tiers() {
+    require_admin();
+    set_key(KEY_VOLUME_TIERS, fee_tiers_from_args());
+}
+
+#[no_mangle]
+pub extern "C" fn set_staking_tiers() {
+    require_admin();
+    set_key(KEY_STAKING_TIERS, fee_tiers_from_args());
+}
+
+#[no_mangle]
+pub extern "C" fn get_fee_tiers() {
+    // (volume tiers, staking tiers) as (threshold, discount bps)
+    let volume_tiers: Vec<(U512, u32)> = get_key(KEY_VOLUME_TIERS);
+    let staking_tiers: Vec<(U512, u32)> = get_key(KEY_STAKING_TIERS);
+    runtime::ret(CLValue::from_t((volume_tiers, staking_tiers)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_trading_volume() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    let now: u64 = runtime::get_blocktime().into();
+    let volume = rolling_volume(&owner, now / DAY_MILLIS);
+    runtime::ret(CLValue::from_t(volume).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_fee_discount() {
+    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    runtime::ret(CLValue::from_t(fee_discount_bps(&owner)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_treasury() {
+    let treasury: U512 = get_key(KEY_TREASURY);
+    runtime::ret(CLValue::from_t(treasury).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_order_book() {
+    // (bids best first, asks best first) as aggregated (price, amount) levels
+    let depth: u64 = runtime::get_named_arg(ARG_DEPTH);
+    let depth = core::cmp::min(depth, MAX_BOOK_ITERATIONS);
+
+    let mut sides: Vec<Vec<(U512, U512)>> = Vec::new();
+    for side in [SIDE_BUY, SIDE_SELL] {
+        let mut levels = Vec::new();
+        let mut price = best_price(side);
+        while !price.is_zero() && (levels.len() as u64) < depth {
+            let level = read_level(side, price).unwrap_or_revert_with(Error::OrderNotFound);
+            levels.push((level.price, level.amount));
+            price = level.next;
+        }
+        sides.push(levels);
+    }
+
+    let asks = sides.pop().unwrap_or_default();
+    let bids = sides.pop().unwrap_or_default();
+    runtime::ret(CLValue::from_t((bids, asks)).unwrap_or_revert());
+}
+
+#[no_mangle]
+pub extern "C" fn get_order() {
+    let order_id: u64 = runtime::get_named_arg(ARG_ORDER_ID);
+    let order = read_order(order_id);
+    runtime::ret(CLValue::from_t(order).unwrap_or_revert());
+}
+
+// Entry points
+fn market_params() -> Vec<Parameter> {
+    vec![
+        Parameter::new(ARG_ORACLE, CLType::Key),
+        Parameter::new(ARG_MAX_LEVERAGE, CLType::U32),
+        Parameter::new(ARG_INITIAL_MARGIN_BPS, CLType::U32),
+        Parameter::new(ARG_MAINTENANCE_MARGIN_BPS, CLType::U32),
+        Parameter::new(ARG_LIQUIDATION_PENALTY_BPS, CLType::U32),
+        Parameter::new(ARG_FUNDING_RATE_BPS, CLType::U32),
+        Parameter::new(ARG_MAKER_FEE_BPS, CLType::U32),
+        Parameter::new(ARG_TAKER_FEE_BPS, CLType::U32),
+        Parameter::new(ARG_PRICE_IMPACT_BPS, CLType::U32),
+        Parameter::new(ARG_MAX_LONG_OPEN_INTEREST, CLType::U512),
+        Parameter::new(ARG_MAX_SHORT_OPEN_INTEREST, CLType::U512),
+    ]
+}
+
+fn create_entry_points() -> EntryPoints {
+    let mut eps = EntryPoints::new();
+    
+    eps.add_entry_point(EntryPoint::new(
+        "name",
+        vec![],
+        CLType::String,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "symbol",
+        vec![],
+        CLType::String,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "decimals",
+        vec![],
+        CLType::U8,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "total_supply",
+        vec![],
+        CLType::U256,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "balance_of",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::U256,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "transfer",
+        vec![
+            Parameter::new(ARG_RECIPIENT, CLType::Key),
+            Parameter::new(ARG_AMOUNT, CLType::U256),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "approve",
+        vec![
+            Parameter::new(ARG_SPENDER, CLType::Key),
+            Parameter::new(ARG_AMOUNT, CLType::U256),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "allowance",
+        vec![
+            Parameter::new(ARG_OWNER, CLType::Key),
+            Parameter::new(ARG_SPENDER, CLType::Key),
+        ],
+        CLType::U256,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "stake",
+        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "unstake",
+        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_stake",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::U512,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "stake_for_fees",
+        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "unstake_fees",
+        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_fee_stake",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::U512,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "open_position",
+        vec![
+            Parameter::new(ARG_MARKET_ID, CLType::U64),
+            Parameter::new(ARG_AMOUNT, CLType::U512),
+            Parameter::new(ARG_LEVERAGE, CLType::U32),
+            Parameter::new(ARG_SIDE, CLType::U8),
+            Parameter::new(ARG_ACCEPTABLE_PRICE, CLType::U512),
+            Parameter::new(ARG_DEADLINE, CLType::U64),
+            Parameter::new(ARG_STOP_LOSS_PRICE, CLType::U512),
+            Parameter::new(ARG_TAKE_PROFIT_PRICE, CLType::U512),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "close_position",
+        vec![
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+            Parameter::new(ARG_ACCEPTABLE_PRICE, CLType::U512),
+            Parameter::new(ARG_DEADLINE, CLType::U64),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "partial_close",
+        vec![
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+            Parameter::new(ARG_SIZE, CLType::U512),
+            Parameter::new(ARG_ACCEPTABLE_PRICE, CLType::U512),
+            Parameter::new(ARG_DEADLINE, CLType::U64),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "increase_position",
+        vec![
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+            Parameter::new(ARG_AMOUNT, CLType::U512),
+            Parameter::new(ARG_LEVERAGE, CLType::U32),
+            Parameter::new(ARG_ACCEPTABLE_PRICE, CLType::U512),
+            Parameter::new(ARG_DEADLINE, CLType::U64),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "add_collateral",
+        vec![
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+            Parameter::new(ARG_AMOUNT, CLType::U512),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "remove_collateral",
+        vec![
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+            Parameter::new(ARG_AMOUNT, CLType::U512),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "liquidate",
+        vec![
+            Parameter::new(ARG_OWNER, CLType::Key),
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_liquidation_price",
+        vec![
+            Parameter::new(ARG_OWNER, CLType::Key),
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+        ],
+        CLType::U512,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_trade_history",
+        vec![
+            Parameter::new(ARG_OWNER, CLType::Key),
+            Parameter::new(ARG_OFFSET, CLType::U64),
+            Parameter::new(ARG_LIMIT, CLType::U64),
+        ],
+        CLType::List(Box::new(CLType::Any)),
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_trade_count",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::U64,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_adl_rank",
+        vec![
+            Parameter::new(ARG_OWNER, CLType::Key),
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+        ],
+        CLType::U64,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "continue_adl",
+        vec![
+            Parameter::new(ARG_MARKET_ID, CLType::U64),
+            Parameter::new(ARG_SIDE, CLType::U8),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_open_interest",
+        vec![Parameter::new(ARG_MARKET_ID, CLType::U64)],
+        CLType::Tuple2([Box::new(CLType::U512), Box::new(CLType::U512)]),
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "update_funding",
+        vec![Parameter::new(ARG_MARKET_ID, CLType::U64)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_funding_index",
+        vec![Parameter::new(ARG_MARKET_ID, CLType::U64)],
+        CLType::I64,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "set_price",
+        vec![
+            Parameter::new(ARG_MARKET_ID, CLType::U64),
+            Parameter::new(ARG_PRICE, CLType::U512),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_insurance_fund",
+        vec![],
+        CLType::U512,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "set_insurance_fee_share",
+        vec![Parameter::new(ARG_INSURANCE_FEE_SHARE_BPS, CLType::U32)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_account_exposure",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::Tuple2([Box::new(CLType::U512), Box::new(CLType::U32)]),
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "set_account_limits",
+        vec![
+            Parameter::new(ARG_MAX_ACCOUNT_NOTIONAL, CLType::U512),
+            Parameter::new(ARG_MAX_POSITIONS_PER_ACCOUNT, CLType::U32),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "set_liquidator_reward",
+        vec![Parameter::new(ARG_LIQUIDATOR_REWARD_BPS, CLType::U32)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    let mut add_market_params = vec![
+        Parameter::new(ARG_BASE_SYMBOL, CLType::String),
+        Parameter::new(ARG_QUOTE_SYMBOL, CLType::String),
+    ];
+    add_market_params.extend(market_params());
+    eps.add_entry_point(EntryPoint::new(
+        "add_market",
+        add_market_params,
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    let mut update_market_params = vec![Parameter::new(ARG_MARKET_ID, CLType::U64)];
+    update_market_params.extend(market_params());
+    eps.add_entry_point(EntryPoint::new(
+        "update_market",
+        update_market_params,
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "set_market_enabled",
+        vec![
+            Parameter::new(ARG_MARKET_ID, CLType::U64),
+            Parameter::new(ARG_ENABLED, CLType::Bool),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_market",
+        vec![Parameter::new(ARG_MARKET_ID, CLType::U64)],
+        CLType::Any,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "list_markets",
+        vec![],
+        CLType::List(Box::new(CLType::Any)),
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "vault_deposit",
+        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "vault_withdraw",
+        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "stake_for_liquid",
+        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "unstake_liquid",
+        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_liquid_stake_ratio",
+        vec![],
+        CLType::U512,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_stcspr_balance",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::U512,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "create_limit_order",
+        vec![
+            Parameter::new(ARG_AMOUNT, CLType::U512),
+            Parameter::new(ARG_PRICE, CLType::U512),
+            Parameter::new(ARG_ORDER_TYPE, CLType::U8),
+            Parameter::new(ARG_TIME_IN_FORCE, CLType::U8),
+            Parameter::new(ARG_EXPIRES_AT, CLType::U64),
+            Parameter::new(ARG_PRICE_HINT, CLType::U512),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "create_stop_loss",
+        vec![
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+            Parameter::new(ARG_SIZE, CLType::U512),
+            Parameter::new(ARG_TRIGGER_PRICE, CLType::U512),
+            Parameter::new(ARG_EXPIRES_AT, CLType::U64),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "create_take_profit",
+        vec![
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+            Parameter::new(ARG_SIZE, CLType::U512),
+            Parameter::new(ARG_TRIGGER_PRICE, CLType::U512),
+            Parameter::new(ARG_EXPIRES_AT, CLType::U64),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "create_trailing_stop",
+        vec![
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+            Parameter::new(ARG_SIZE, CLType::U512),
+            Parameter::new(ARG_TRAIL_AMOUNT, CLType::U512),
+            Parameter::new(ARG_TRAIL_BPS, CLType::U32),
+            Parameter::new(ARG_EXPIRES_AT, CLType::U64),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "update_trailing_stop",
+        vec![Parameter::new(ARG_ORDER_ID, CLType::U64)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
     
-    let counter: u64 = get_key(KEY_ORDER_COUNTER);
-    let order_id = counter + 1;
-    set_key(KEY_ORDER_COUNTER, order_id);
+    eps.add_entry_point(EntryPoint::new(
+        "create_oco",
+        vec![
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+            Parameter::new(ARG_SIZE, CLType::U512),
+            Parameter::new(ARG_STOP_LOSS_PRICE, CLType::U512),
+            Parameter::new(ARG_TAKE_PROFIT_PRICE, CLType::U512),
+            Parameter::new(ARG_EXPIRES_AT, CLType::U64),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
     
-    let dict = get_dict(DICT_ORDERS);
-    let key = format!("{}_{}", make_key(&caller), order_id);
-    let order_data = format!("{}:{}:{}", amount, price, order_type);
-    storage::dictionary_put(dict, &key, order_data);
-}
-
-#[no_mangle]
-pub extern "C" fn create_stop_loss() {
-    let amount: U512 = runtime::get_named_arg(ARG_AMOUNT);
-    let trigger_price: U512 = runtime::get_named_arg(ARG_TRIGGER_PRICE);
-    let caller = Key::from(runtime::get_caller());
+    eps.add_entry_point(EntryPoint::new(
+        "create_recurring_order",
+        vec![
+            Parameter::new(ARG_ASSET, CLType::U8),
+            Parameter::new(ARG_SIDE, CLType::U8),
+            Parameter::new(ARG_AMOUNT, CLType::U512),
+            Parameter::new(ARG_SLICE_AMOUNT, CLType::U512),
+            Parameter::new(ARG_INTERVAL, CLType::U64),
+            Parameter::new(ARG_MIN_PRICE, CLType::U512),
+            Parameter::new(ARG_MAX_PRICE, CLType::U512),
+        ],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
     
-    let counter: u64 = get_key(KEY_ORDER_COUNTER);
-    let order_id = counter + 1;
-    set_key(KEY_ORDER_COUNTER, order_id);
+    eps.add_entry_point(EntryPoint::new(
+        "execute_slice",
+        vec![Parameter::new(ARG_ORDER_ID, CLType::U64)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
     
-    let dict = get_dict(DICT_ORDERS);
-    let key = format!("{}_{}", make_key(&caller), order_id);
-    let order_data = format!("{}:{}:stop", amount, trigger_price);
-    storage::dictionary_put(dict, &key, order_data);
-}
-
-#[no_mangle]
-pub extern "C" fn cancel_order() {
-    let order_id: u64 = runtime::get_named_arg(ARG_ORDER_ID);
-    let caller = Key::from(runtime::get_caller());
+    eps.add_entry_point(EntryPoint::new(
+        "get_position_orders",
+        vec![
+            Parameter::new(ARG_OWNER, CLType::Key),
+            Parameter::new(ARG_POSITION_ID, CLType::U64),
+        ],
+        CLType::Tuple2([Box::new(CLType::U64), Box::new(CLType::U64)]),
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
     
-    let dict = get_dict(DICT_ORDERS);
-    let key = format!("{}_{}", make_key(&caller), order_id);
-    storage::dictionary_put(dict, &key, String::from("cancelled"));
-}
-
-#[no_mangle]
-pub extern "C" fn execute_order() {
-    let order_id: u64 = runtime::get_named_arg(ARG_ORDER_ID);
-    let owner: Key = runtime::get_named_arg(ARG_OWNER);
+    eps.add_entry_point(EntryPoint::new(
+        "cancel_order",
+        vec![Parameter::new(ARG_ORDER_ID, CLType::U64)],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
     
-    let dict = get_dict(DICT_ORDERS);
-    let key = format!("{}_{}", make_key(&owner), order_id);
-    storage::dictionary_put(dict, &key, String::from("executed"));
-}
-// Entry points
-fn create_entry_points() -> EntryPoints {
-    let mut eps = EntryPoints::new();
+    eps.add_entry_point(EntryPoint::new(
+        "place_order",
+        vec![
+            Parameter::new(ARG_AMOUNT, CLType::U512),
+            Parameter::new(ARG_PRICE, CLType::U512),
+            Parameter::new(ARG_SIDE, CLType::U8),
+            Parameter::new(ARG_TIME_IN_FORCE, CLType::U8),
+            Parameter::new(ARG_EXPIRES_AT, CLType::U64),
+            Parameter::new(ARG_PRICE_HINT, CLType::U512),
+        ],
+        CLType::U64,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "name",
-        vec![],
-        CLType::String,
+        "settle_orders",
+        vec![
+            Parameter::new(ARG_MAKER_ORDER, CLType::Any),
+            Parameter::new(ARG_TAKER_ORDER, CLType::Any),
+            Parameter::new(ARG_SIGNATURES, CLType::List(Box::new(CLType::Any))),
+        ],
+        CLType::Unit,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "symbol",
-        vec![],
-        CLType::String,
+        "cancel_signed_orders",
+        vec![Parameter::new(ARG_NONCE, CLType::U64)],
+        CLType::Unit,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "decimals",
+        "get_signing_domain",
         vec![],
-        CLType::U8,
+        CLType::Tuple2([Box::new(CLType::String), Box::new(CLType::Key)]),
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "total_supply",
-        vec![],
-        CLType::U256,
+        "get_order_nonce",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::U64,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "balance_of",
+        "get_signed_order_fill",
+        vec![Parameter::new(ARG_ORDER_HASH, CLType::String)],
+        CLType::U512,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_orders",
+        vec![
+            Parameter::new(ARG_OWNER, CLType::Key),
+            Parameter::new(ARG_STATUS_FILTER, CLType::U8),
+            Parameter::new(ARG_OFFSET, CLType::U64),
+            Parameter::new(ARG_LIMIT, CLType::U64),
+        ],
+        CLType::List(Box::new(CLType::Any)),
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_open_order_count",
         vec![Parameter::new(ARG_OWNER, CLType::Key)],
-        CLType::U256,
+        CLType::U64,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "transfer",
+        "get_order_count",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::U64,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "set_spot_fees",
         vec![
-            Parameter::new(ARG_RECIPIENT, CLType::Key),
-            Parameter::new(ARG_AMOUNT, CLType::U256),
+            Parameter::new(ARG_MAKER_FEE_BPS, CLType::U32),
+            Parameter::new(ARG_TAKER_FEE_BPS, CLType::U32),
+            Parameter::new(ARG_MAKER_REBATE_BPS, CLType::U32),
         ],
         CLType::Unit,
         EntryPointAccess::Public,
@@ -487,10 +5448,18 @@ fn create_entry_points() -> EntryPoints {
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "approve",
+        "get_spot_fees",
+        vec![],
+        CLType::Tuple3([Box::new(CLType::U32), Box::new(CLType::U32), Box::new(CLType::U32)]),
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "set_volume_tiers",
         vec![
-            Parameter::new(ARG_SPENDER, CLType::Key),
-            Parameter::new(ARG_AMOUNT, CLType::U256),
+            Parameter::new(ARG_THRESHOLDS, CLType::List(Box::new(CLType::U512))),
+            Parameter::new(ARG_DISCOUNTS_BPS, CLType::List(Box::new(CLType::U32))),
         ],
         CLType::Unit,
         EntryPointAccess::Public,
@@ -498,45 +5467,84 @@ fn create_entry_points() -> EntryPoints {
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "allowance",
+        "set_staking_tiers",
         vec![
-            Parameter::new(ARG_OWNER, CLType::Key),
-            Parameter::new(ARG_SPENDER, CLType::Key),
+            Parameter::new(ARG_THRESHOLDS, CLType::List(Box::new(CLType::U512))),
+            Parameter::new(ARG_DISCOUNTS_BPS, CLType::List(Box::new(CLType::U32))),
         ],
-        CLType::U256,
+        CLType::Unit,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
+    let fee_tiers = CLType::List(Box::new(CLType::Tuple2([
+        Box::new(CLType::U512),
+        Box::new(CLType::U32),
+    ])));
     eps.add_entry_point(EntryPoint::new(
-        "stake",
-        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
-        CLType::Unit,
+        "get_fee_tiers",
+        vec![],
+        CLType::Tuple2([Box::new(fee_tiers.clone()), Box::new(fee_tiers)]),
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "unstake",
-        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
-        CLType::Unit,
+        "get_trading_volume",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::U512,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "get_stake",
+        "get_fee_discount",
         vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::U32,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "get_treasury",
+        vec![],
         CLType::U512,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "open_position",
+        "get_order_book",
+        vec![Parameter::new(ARG_DEPTH, CLType::U64)],
+        CLType::Tuple2([
+            Box::new(CLType::List(Box::new(CLType::Tuple2([
+                Box::new(CLType::U512),
+                Box::new(CLType::U512),
+            ])))),
+            Box::new(CLType::List(Box::new(CLType::Tuple2([
+                Box::new(CLType::U512),
+                Box::new(CLType::U512),
+            ])))),
+        ]),
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "cancel_orders",
+        vec![Parameter::new(ARG_ORDER_IDS, CLType::List(Box::new(CLType::U64)))],
+        CLType::Unit,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "modify_order",
         vec![
-            Parameter::new(ARG_AMOUNT, CLType::U512),
-            Parameter::new("leverage", CLType::U32),
+            Parameter::new(ARG_ORDER_ID, CLType::U64),
+            Parameter::new(ARG_NEW_PRICE, CLType::U512),
+            Parameter::new(ARG_NEW_AMOUNT, CLType::U512),
+            Parameter::new(ARG_PRICE_HINT, CLType::U512),
         ],
         CLType::Unit,
         EntryPointAccess::Public,
@@ -544,39 +5552,61 @@ fn create_entry_points() -> EntryPoints {
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "close_position",
-        vec![Parameter::new("position_id", CLType::U64)],
-        CLType::Unit,
+        "batch_orders",
+        vec![
+            Parameter::new(ARG_INSTRUCTIONS, CLType::List(Box::new(CLType::Any))),
+            Parameter::new(ARG_ATOMIC, CLType::Bool),
+        ],
+        CLType::List(Box::new(CLType::Tuple2([Box::new(CLType::U64), Box::new(CLType::U32)]))),
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "vault_deposit",
-        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        "cancel_all",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::U64,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "execute_order",
+        vec![
+            Parameter::new(ARG_ORDER_ID, CLType::U64),
+            Parameter::new(ARG_OWNER, CLType::Key),
+        ],
         CLType::Unit,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "vault_withdraw",
-        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        "reclaim_expired_order",
+        vec![Parameter::new(ARG_ORDER_ID, CLType::U64)],
         CLType::Unit,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "stake_for_liquid",
-        vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
+        "set_expiry_bounty",
+        vec![Parameter::new(ARG_EXPIRY_BOUNTY_BPS, CLType::U32)],
         CLType::Unit,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "unstake_liquid",
+        "get_locked_balance",
+        vec![Parameter::new(ARG_OWNER, CLType::Key)],
+        CLType::Tuple2([Box::new(CLType::U512), Box::new(CLType::U256)]),
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "register_keeper",
         vec![Parameter::new(ARG_AMOUNT, CLType::U512)],
         CLType::Unit,
         EntryPointAccess::Public,
@@ -584,27 +5614,28 @@ fn create_entry_points() -> EntryPoints {
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "get_liquid_stake_ratio",
+        "request_keeper_exit",
         vec![],
-        CLType::U512,
+        CLType::Unit,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "get_stcspr_balance",
-        vec![Parameter::new(ARG_OWNER, CLType::Key)],
-        CLType::U512,
+        "withdraw_keeper_bond",
+        vec![],
+        CLType::Unit,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "create_limit_order",
+        "slash_keeper",
         vec![
+            Parameter::new(ARG_KEEPER, CLType::Key),
             Parameter::new(ARG_AMOUNT, CLType::U512),
-            Parameter::new(ARG_PRICE, CLType::U512),
-            Parameter::new(ARG_ORDER_TYPE, CLType::U8),
+            Parameter::new(ARG_REASON, CLType::String),
+            Parameter::new(ARG_EVIDENCE, CLType::String),
         ],
         CLType::Unit,
         EntryPointAccess::Public,
@@ -612,35 +5643,59 @@ fn create_entry_points() -> EntryPoints {
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "create_stop_loss",
+        "get_keeper_slash",
         vec![
-            Parameter::new(ARG_AMOUNT, CLType::U512),
-            Parameter::new(ARG_TRIGGER_PRICE, CLType::U512),
+            Parameter::new(ARG_KEEPER, CLType::Key),
+            Parameter::new(ARG_INDEX, CLType::U64),
         ],
-        CLType::Unit,
+        CLType::Tuple3([
+            Box::new(CLType::U512),
+            Box::new(CLType::String),
+            Box::new(CLType::String),
+        ]),
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "cancel_order",
-        vec![Parameter::new(ARG_ORDER_ID, CLType::U64)],
+        "set_keeper_params",
+        vec![
+            Parameter::new(ARG_MIN_KEEPER_BOND, CLType::U512),
+            Parameter::new(ARG_EXECUTION_FEE, CLType::U512),
+            Parameter::new(ARG_FUNDING_UPDATE_REWARD, CLType::U512),
+        ],
         CLType::Unit,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
     eps.add_entry_point(EntryPoint::new(
-        "execute_order",
+        "keeper_stats",
+        vec![Parameter::new(ARG_KEEPER, CLType::Key)],
+        CLType::Any,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
+    eps.add_entry_point(EntryPoint::new(
+        "set_keeper",
         vec![
-            Parameter::new(ARG_ORDER_ID, CLType::U64),
-            Parameter::new(ARG_OWNER, CLType::Key),
+            Parameter::new(ARG_KEEPER, CLType::Key),
+            Parameter::new(ARG_ENABLED, CLType::Bool),
         ],
         CLType::Unit,
         EntryPointAccess::Public,
         EntryPointType::Called,
     ).into());
     
+    eps.add_entry_point(EntryPoint::new(
+        "get_order",
+        vec![Parameter::new(ARG_ORDER_ID, CLType::U64)],
+        CLType::Any,
+        EntryPointAccess::Public,
+        EntryPointType::Called,
+    ).into());
+    
     eps
 }
 
@@ -651,6 +5706,7 @@ pub extern "C" fn call() {
     let token_name: String = runtime::get_named_arg(ARG_TOKEN_NAME);
     let token_symbol: String = runtime::get_named_arg(ARG_TOKEN_SYMBOL);
     let total_supply: U256 = runtime::get_named_arg(ARG_TOTAL_SUPPLY);
+    let chain_name: String = runtime::get_named_arg(ARG_CHAIN_NAME);
     let admin: Key = runtime::get_caller().into();
     
     let mut named_keys = NamedKeys::new();
@@ -667,6 +5723,34 @@ pub extern "C" fn call() {
     named_keys.insert(KEY_LIQUID_STAKING_RATIO.into(), storage::new_uref(U512::from(1000000u64)).into());
     named_keys.insert(KEY_TOTAL_LIQUID_STAKED.into(), storage::new_uref(U512::zero()).into());
     named_keys.insert(KEY_ORDER_COUNTER.into(), storage::new_uref(0u64).into());
+    named_keys.insert(KEY_BEST_BID.into(), storage::new_uref(U512::zero()).into());
+    named_keys.insert(KEY_BEST_ASK.into(), storage::new_uref(U512::zero()).into());
+    named_keys.insert(KEY_POSITION_COUNTER.into(), storage::new_uref(0u64).into());
+    named_keys.insert(KEY_LIQUIDATOR_REWARD_BPS.into(), storage::new_uref(5000u32).into());
+    named_keys.insert(KEY_EXPIRY_BOUNTY_BPS.into(), storage::new_uref(10u32).into());
+    // 100 CSPR minimum bond, 0.1 CSPR per execution and funding update
+    let min_keeper_bond = U512::from(100_000_000_000u64);
+    named_keys.insert(KEY_MIN_KEEPER_BOND.into(), storage::new_uref(min_keeper_bond).into());
+    let execution_fee = U512::from(100_000_000u64);
+    named_keys.insert(KEY_EXECUTION_FEE.into(), storage::new_uref(execution_fee).into());
+    named_keys.insert(KEY_FUNDING_UPDATE_REWARD.into(), storage::new_uref(execution_fee).into());
+    // The package hash is only known once the contract exists, see below
+    let signing_domain = storage::new_uref((chain_name.clone(), Key::Hash([0u8; 32])));
+    named_keys.insert(KEY_SIGNING_DOMAIN.into(), signing_domain.into());
+    named_keys.insert(KEY_INSURANCE_FUND.into(), storage::new_uref(U512::zero()).into());
+    named_keys.insert(KEY_MARKET_COUNTER.into(), storage::new_uref(0u64).into());
+    named_keys.insert(KEY_TREASURY.into(), storage::new_uref(U512::zero()).into());
+    named_keys.insert(KEY_INSURANCE_FEE_SHARE_BPS.into(), storage::new_uref(2000u32).into());
+    named_keys.insert(KEY_MAX_ACCOUNT_NOTIONAL.into(), storage::new_uref(U512::zero()).into());
+    named_keys.insert(KEY_MAX_POSITIONS_PER_ACCOUNT.into(), storage::new_uref(0u32).into());
+    // Spot makers trade free and takers pay 10 bps until the admin sets a schedule
+    named_keys.insert(KEY_SPOT_MAKER_FEE_BPS.into(), storage::new_uref(0u32).into());
+    named_keys.insert(KEY_SPOT_TAKER_FEE_BPS.into(), storage::new_uref(10u32).into());
+    named_keys.insert(KEY_SPOT_MAKER_REBATE_BPS.into(), storage::new_uref(0u32).into());
+    let no_tiers: Vec<(U512, u32)> = Vec::new();
+    named_keys.insert(KEY_VOLUME_TIERS.into(), storage::new_uref(no_tiers.clone()).into());
+    named_keys.insert(KEY_STAKING_TIERS.into(), storage::new_uref(no_tiers).into());
+    named_keys.insert(KEY_TOTAL_FEE_STAKED.into(), storage::new_uref(U512::zero()).into());
     
     let balances = storage::new_dictionary(DICT_BALANCES).unwrap_or_revert();
     named_keys.insert(DICT_BALANCES.into(), balances.into());
@@ -678,6 +5762,9 @@ pub extern "C" fn call() {
     let stakers = storage::new_dictionary(DICT_STAKERS).unwrap_or_revert();
     named_keys.insert(DICT_STAKERS.into(), stakers.into());
     
+    let fee_stakes = storage::new_dictionary(DICT_FEE_STAKES).unwrap_or_revert();
+    named_keys.insert(DICT_FEE_STAKES.into(), fee_stakes.into());
+    
     let positions = storage::new_dictionary(DICT_POSITIONS).unwrap_or_revert();
     named_keys.insert(DICT_POSITIONS.into(), positions.into());
     
@@ -693,15 +5780,105 @@ pub extern "C" fn call() {
     let orders = storage::new_dictionary(DICT_ORDERS).unwrap_or_revert();
     named_keys.insert(DICT_ORDERS.into(), orders.into());
     
+    let markets = storage::new_dictionary(DICT_MARKETS).unwrap_or_revert();
+    named_keys.insert(DICT_MARKETS.into(), markets.into());
+    
+    let market_state = storage::new_dictionary(DICT_MARKET_STATE).unwrap_or_revert();
+    named_keys.insert(DICT_MARKET_STATE.into(), market_state.into());
+    let spot_state = MarketState::new(runtime::get_blocktime().into());
+    storage::dictionary_put(market_state, &format!("{}", SPOT_MARKET_ID), spot_state);
+    
+    let account_notional = storage::new_dictionary(DICT_ACCOUNT_NOTIONAL).unwrap_or_revert();
+    named_keys.insert(DICT_ACCOUNT_NOTIONAL.into(), account_notional.into());
+    
+    let account_positions = storage::new_dictionary(DICT_ACCOUNT_POSITIONS).unwrap_or_revert();
+    named_keys.insert(DICT_ACCOUNT_POSITIONS.into(), account_positions.into());
+    
+    let market_positions = storage::new_dictionary(DICT_MARKET_POSITIONS).unwrap_or_revert();
+    named_keys.insert(DICT_MARKET_POSITIONS.into(), market_positions.into());
+    
+    let market_position_count = storage::new_dictionary(DICT_MARKET_POSITION_COUNT).unwrap_or_revert();
+    named_keys.insert(DICT_MARKET_POSITION_COUNT.into(), market_position_count.into());
+    
+    let position_slots = storage::new_dictionary(DICT_POSITION_SLOTS).unwrap_or_revert();
+    named_keys.insert(DICT_POSITION_SLOTS.into(), position_slots.into());
+    
+    let trade_history = storage::new_dictionary(DICT_TRADE_HISTORY).unwrap_or_revert();
+    named_keys.insert(DICT_TRADE_HISTORY.into(), trade_history.into());
+    
+    let trade_count = storage::new_dictionary(DICT_TRADE_COUNT).unwrap_or_revert();
+    named_keys.insert(DICT_TRADE_COUNT.into(), trade_count.into());
+    
+    let keepers = storage::new_dictionary(DICT_KEEPERS).unwrap_or_revert();
+    named_keys.insert(DICT_KEEPERS.into(), keepers.into());
+    
+    let keeper_slashes = storage::new_dictionary(DICT_KEEPER_SLASHES).unwrap_or_revert();
+    named_keys.insert(DICT_KEEPER_SLASHES.into(), keeper_slashes.into());
+    
+    let funding_rewards = storage::new_dictionary(DICT_FUNDING_REWARDS).unwrap_or_revert();
+    named_keys.insert(DICT_FUNDING_REWARDS.into(), funding_rewards.into());
+    
+    let adl_queue = storage::new_dictionary(DICT_ADL_QUEUE).unwrap_or_revert();
+    named_keys.insert(DICT_ADL_QUEUE.into(), adl_queue.into());
+    
+    let locked_cspr = storage::new_dictionary(DICT_LOCKED_CSPR).unwrap_or_revert();
+    named_keys.insert(DICT_LOCKED_CSPR.into(), locked_cspr.into());
+    
+    let locked_cflow = storage::new_dictionary(DICT_LOCKED_CFLOW).unwrap_or_revert();
+    named_keys.insert(DICT_LOCKED_CFLOW.into(), locked_cflow.into());
+    
+    let open_orders = storage::new_dictionary(DICT_OPEN_ORDERS).unwrap_or_revert();
+    named_keys.insert(DICT_OPEN_ORDERS.into(), open_orders.into());
+    
+    let open_order_count = storage::new_dictionary(DICT_OPEN_ORDER_COUNT).unwrap_or_revert();
+    named_keys.insert(DICT_OPEN_ORDER_COUNT.into(), open_order_count.into());
+    
+    let order_slots = storage::new_dictionary(DICT_ORDER_SLOTS).unwrap_or_revert();
+    named_keys.insert(DICT_ORDER_SLOTS.into(), order_slots.into());
+    
+    let book_levels = storage::new_dictionary(DICT_BOOK_LEVELS).unwrap_or_revert();
+    named_keys.insert(DICT_BOOK_LEVELS.into(), book_levels.into());
+    
+    let book_links = storage::new_dictionary(DICT_BOOK_LINKS).unwrap_or_revert();
+    named_keys.insert(DICT_BOOK_LINKS.into(), book_links.into());
+    
+    let position_orders = storage::new_dictionary(DICT_POSITION_ORDERS).unwrap_or_revert();
+    named_keys.insert(DICT_POSITION_ORDERS.into(), position_orders.into());
+    
+    let order_nonces = storage::new_dictionary(DICT_ORDER_NONCES).unwrap_or_revert();
+    named_keys.insert(DICT_ORDER_NONCES.into(), order_nonces.into());
+    
+    let signed_fills = storage::new_dictionary(DICT_SIGNED_FILLS).unwrap_or_revert();
+    named_keys.insert(DICT_SIGNED_FILLS.into(), signed_fills.into());
+    
+    let owner_orders = storage::new_dictionary(DICT_OWNER_ORDERS).unwrap_or_revert();
+    named_keys.insert(DICT_OWNER_ORDERS.into(), owner_orders.into());
+    
+    let owner_order_count = storage::new_dictionary(DICT_OWNER_ORDER_COUNT).unwrap_or_revert();
+    named_keys.insert(DICT_OWNER_ORDER_COUNT.into(), owner_order_count.into());
+    
+    let volume_buckets = storage::new_dictionary(DICT_VOLUME_BUCKETS).unwrap_or_revert();
+    named_keys.insert(DICT_VOLUME_BUCKETS.into(), volume_buckets.into());
+    
+    let volume_totals = storage::new_dictionary(DICT_VOLUME_TOTALS).unwrap_or_revert();
+    named_keys.insert(DICT_VOLUME_TOTALS.into(), volume_totals.into());
+    
     let entry_points = create_entry_points();
     
+    let mut message_topics = BTreeMap::new();
+    message_topics.insert(EVENTS_TOPIC.into(), MessageTopicOperation::Add);
+    
     let (contract_hash, _version) = storage::new_contract(
         entry_points,
         Some(named_keys),
         Some(contract_name.clone()),
         Some(format!("{}_access", contract_name)),
-        None,
+        Some(message_topics),
     );
     
+    // new_contract stores the package hash under the contract name
+    let package_hash = runtime::get_key(&contract_name).unwrap_or_revert();
+    storage::write(signing_domain, (chain_name, package_hash));
+    
     runtime::put_key(&contract_name, contract_hash.into());
 }
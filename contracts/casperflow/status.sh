@@ -32,6 +32,7 @@ echo "    --session-arg 'contract_name:string=\"casperflow\"' \\"
 echo "    --session-arg 'token_name:string=\"CasperFlow Token\"' \\"
 echo "    --session-arg 'token_symbol:string=\"CFLOW\"' \\"
 echo "    --session-arg 'total_supply:u256=\"1000000000000000000\"' \\"
+echo "    --session-arg 'chain_name:string=\"casper-test\"' \\"
 echo "    --output casperflow-deploy.json"
 echo ""
 echo "Contract Features:"